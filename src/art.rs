@@ -0,0 +1,284 @@
+use base64::Engine;
+use percent_encoding::percent_decode_str;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Larger images are not downloaded, local ones are used in place.
+const MAX_IMAGE_SIZE: u64 = 10 * 1024 * 1024;
+/// When exceeded, least recently used images are removed from cache.
+const MAX_CACHE_SIZE: u64 = 100 * 1024 * 1024;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Local copies of cover art, named by hash of their content.
+pub struct ArtCache {
+    dir: PathBuf,
+    fallback: Option<PathBuf>,
+    /// downloaded urls, so each is fetched once
+    fetched: HashMap<String, PathBuf>,
+}
+
+impl ArtCache {
+    pub fn new(dir: PathBuf, fallback: Option<PathBuf>) -> Result<Self, Box<dyn Error>> {
+        fs::create_dir_all(&dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+
+        Ok(Self {
+            dir,
+            fallback,
+            fetched: HashMap::new(),
+        })
+    }
+
+    /// Image to use when the player reports none or it can't be cached.
+    pub fn fallback(&self) -> Option<&Path> {
+        self.fallback.as_deref()
+    }
+
+    /// Return local file with the image from `url`. Blocks while reading or downloading it.
+    pub fn resolve(&mut self, url: &str) -> Result<PathBuf, Box<dyn Error>> {
+        if let Some(path) = self.fetched.get(url)
+            && path.exists()
+        {
+            touch(path)?;
+            return Ok(path.clone());
+        }
+
+        let content = if let Some(path) = url.strip_prefix("file://") {
+            // `localhost` is the only host a file URL may name, same as none
+            let path = path
+                .strip_prefix("localhost")
+                .filter(|path| path.starts_with('/'))
+                .unwrap_or(path);
+            let path = PathBuf::from(percent_decode_str(path).decode_utf8()?.into_owned());
+            if fs::metadata(&path)?.len() > MAX_IMAGE_SIZE {
+                return Ok(path);
+            }
+            // players may remove the file on track change, so it's copied
+            fs::read(&path)?
+        } else if let Some(data) = url.strip_prefix("data:") {
+            decode_data_uri(data)?
+        } else if url.starts_with("http://") || url.starts_with("https://") {
+            let content = fetch(url)?;
+            let path = self.store(&content)?;
+            self.fetched.insert(url.to_owned(), path.clone());
+            return Ok(path);
+        } else {
+            return Err(format!("unsupported url {:?}", url).into());
+        };
+
+        self.store(&content)
+    }
+
+    fn store(&mut self, content: &[u8]) -> Result<PathBuf, Box<dyn Error>> {
+        let name = Sha256::digest(content)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        let path = self.dir.join(name);
+
+        if path.exists() {
+            touch(&path)?;
+        } else {
+            // write under temporary name, so other readers never see partial file
+            let partial = path.with_extension(format!("{}.part", std::process::id()));
+            fs::write(&partial, content)?;
+            fs::rename(&partial, &path)?;
+            self.trim(&path)?;
+        }
+
+        Ok(path)
+    }
+
+    /// Remove least recently used images until cache fits in `MAX_CACHE_SIZE`, except `keep`.
+    fn trim(&mut self, keep: &Path) -> Result<(), Box<dyn Error>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_file() {
+                files.push((metadata.modified()?, metadata.len(), entry.path()));
+            }
+        }
+
+        let mut size = files.iter().map(|(_, len, _)| len).sum::<u64>();
+        files.sort();
+        for (_, len, path) in files {
+            if size <= MAX_CACHE_SIZE {
+                break;
+            }
+            if path != keep {
+                fs::remove_file(&path)?;
+                size -= len;
+            }
+        }
+
+        self.fetched.retain(|_, path| path.exists());
+        Ok(())
+    }
+}
+
+/// Mark cached image as recently used.
+fn touch(path: &Path) -> std::io::Result<()> {
+    fs::File::options()
+        .append(true)
+        .open(path)?
+        .set_modified(SystemTime::now())
+}
+
+/// Decode content of `data:[<mediatype>][;base64],<data>` URI, without the `data:` prefix.
+fn decode_data_uri(data: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let (media_type, data) = data.split_once(',').ok_or("invalid data uri")?;
+
+    let content = if media_type.ends_with(";base64") {
+        let data = percent_decode_str(data)
+            .filter(|b| !b.is_ascii_whitespace())
+            .collect::<Vec<_>>();
+        base64::engine::general_purpose::STANDARD.decode(data)?
+    } else {
+        percent_decode_str(data).collect()
+    };
+
+    if content.len() as u64 > MAX_IMAGE_SIZE {
+        return Err("image too large".into());
+    }
+    Ok(content)
+}
+
+fn fetch(url: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let response = ureq::get(url).timeout(FETCH_TIMEOUT).call()?;
+
+    if let Some(length) = response.header("Content-Length")
+        && length
+            .parse::<u64>()
+            .is_ok_and(|length| length > MAX_IMAGE_SIZE)
+    {
+        return Err("image too large".into());
+    }
+
+    let mut content = Vec::new();
+    response
+        .into_reader()
+        .take(MAX_IMAGE_SIZE + 1)
+        .read_to_end(&mut content)?;
+    if content.len() as u64 > MAX_IMAGE_SIZE {
+        return Err("image too large".into());
+    }
+
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn cache_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "player_watcher-art-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// Serve `body` over HTTP on localhost, return the url and number of requests served.
+    fn serve(body: &'static [u8]) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/cover.png", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+
+        let counter = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )
+                .unwrap();
+                stream.write_all(body).unwrap();
+            }
+        });
+
+        (url, requests)
+    }
+
+    #[test]
+    fn decodes_data_uris() {
+        let dir = cache_dir("data");
+        let mut cache = ArtCache::new(dir.clone(), None).unwrap();
+
+        let path = cache.resolve("data:image/png;base64,aW1hZ2U=").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"image");
+        assert!(path.starts_with(&dir));
+
+        let path = cache.resolve("data:,im%61ge").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"image");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn copies_local_files() {
+        let dir = cache_dir("file");
+        let source = dir.with_extension("cover image.png");
+        fs::write(&source, b"local image").unwrap();
+        let mut cache = ArtCache::new(dir.clone(), None).unwrap();
+
+        let url = format!("file://{}", source.display()).replace(' ', "%20");
+        let path = cache.resolve(&url).unwrap();
+        fs::remove_file(&source).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"local image");
+        assert!(path.starts_with(&dir));
+
+        fs::write(&source, b"local image").unwrap();
+        let url = url.replace("file://", "file://localhost");
+        assert_eq!(cache.resolve(&url).unwrap(), path);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn fetches_once() {
+        let dir = cache_dir("http");
+        let (url, requests) = serve(b"remote image");
+        let mut cache = ArtCache::new(dir.clone(), None).unwrap();
+
+        let path = cache.resolve(&url).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"remote image");
+        assert_eq!(cache.resolve(&url).unwrap(), path);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // same content is stored once
+        let copy = cache.resolve("data:;base64,cmVtb3RlIGltYWdl").unwrap();
+        assert_eq!(copy, path);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_unsupported_urls() {
+        let dir = cache_dir("unsupported");
+        let mut cache = ArtCache::new(dir.clone(), None).unwrap();
+
+        assert!(cache.resolve("ftp://example.com/cover.png").is_err());
+        assert!(cache.resolve("data:image/png;base64").is_err());
+        assert!(cache.resolve("file:///nonexistent/cover.png").is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,91 @@
+use dbus::channel::{Channel, MatchingReceiver};
+use dbus::nonblock::{MsgMatch, SyncConnection};
+use dbus_tokio::connection;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Completes with the reason when the connection is lost.
+pub type Disconnected = oneshot::Receiver<String>;
+
+/// Connect to the session bus, for programs which reconnect when the connection is lost.
+pub fn connect() -> Result<(Arc<SyncConnection>, Disconnected), dbus::Error> {
+    let (resource, connection) = connection::new_session_sync()?;
+    let (sender, disconnected) = oneshot::channel();
+
+    tokio::spawn(async move {
+        let err = resource.await;
+        let _ = sender.send(err.to_string());
+    });
+
+    Ok((connection, disconnected))
+}
+
+/// Connect to the session bus for a single command, exit with error when the connection is lost.
+pub fn connect_once() -> Result<Arc<SyncConnection>, dbus::Error> {
+    let (resource, connection) = connection::new_session_sync()?;
+
+    tokio::spawn(async {
+        let err = resource.await;
+        eprintln!("Error: lost connection to D-Bus: {}", err);
+        std::process::exit(1);
+    });
+
+    Ok(connection)
+}
+
+pub fn is_connected(connection: &SyncConnection) -> bool {
+    let channel: &Channel = connection.as_ref();
+    channel.is_connected()
+}
+
+/// Remove `listens` and drop their callbacks. Once the connection is lost, the bus forgets the
+/// matches by itself, so only the callbacks are dropped.
+pub async fn stop_listening(
+    connection: &SyncConnection,
+    listens: impl IntoIterator<Item = MsgMatch>,
+) {
+    for listen in listens {
+        if !is_connected(connection) {
+            connection.stop_receive(listen.token());
+        } else if let Err(err) = connection.remove_match(listen.token()).await {
+            eprintln!("cannot stop listening: {}", err);
+        }
+    }
+}
+
+/// Delay between reconnection attempts, doubled after each one.
+pub struct Backoff {
+    delay: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            delay: MIN_RECONNECT_DELAY,
+        }
+    }
+}
+
+impl Backoff {
+    pub async fn wait(&mut self) {
+        tokio::time::sleep(self.delay).await;
+        self.delay = (self.delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+
+    pub fn reset(&mut self) {
+        self.delay = MIN_RECONNECT_DELAY;
+    }
+}
+
+/// Properties as sent in `PropertiesChanged`, for tests of their handling.
+#[cfg(test)]
+pub fn props(entries: Vec<(&str, Box<dyn dbus::arg::RefArg>)>) -> dbus::arg::PropMap {
+    entries
+        .into_iter()
+        .map(|(key, value)| (key.to_owned(), dbus::arg::Variant(value)))
+        .collect()
+}
@@ -0,0 +1,209 @@
+use crate::players::{player_identity, short_name};
+use glob::Pattern;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+/// Config file as written by the user, see README for the format.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawConfig {
+    ignore: Vec<String>,
+    priority: Vec<String>,
+    players: BTreeMap<String, PlayerOverride>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PlayerOverride {
+    ignore: Option<bool>,
+    priority: Option<i32>,
+    auto_activate: Option<bool>,
+}
+
+/// How the daemon treats a single player.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PlayerRules {
+    pub ignored: bool,
+    /// when several players are playing, the one with higher priority stays active
+    pub priority: i32,
+    /// whether the player becomes active when it starts playing
+    pub auto_activate: bool,
+}
+
+impl Default for PlayerRules {
+    fn default() -> Self {
+        Self {
+            ignored: false,
+            priority: 0,
+            auto_activate: true,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Config {
+    ignore: Vec<Pattern>,
+    priority: Vec<Pattern>,
+    players: Vec<(Pattern, PlayerOverride)>,
+}
+
+fn compile(pattern: &str) -> Result<Pattern, Box<dyn Error>> {
+    Pattern::new(pattern).map_err(|e| format!("invalid pattern {:?}: {}", pattern, e).into())
+}
+
+/// Patterns are checked against the full bus name, the name without `MPRIS_PREFIX` and the
+/// player identity, so both `*.kdeconnect*` and `spotify` work as expected.
+fn matches(pattern: &Pattern, name: &str) -> bool {
+    pattern.matches(name)
+        || pattern.matches(short_name(name))
+        || pattern.matches(player_identity(name))
+}
+
+/// Base directory from XDG variable `var`, or `home_fallback` under the home directory if unset.
+pub fn xdg_dir(var: &str, home_fallback: &str) -> Option<PathBuf> {
+    std::env::var_os(var)
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(home_fallback)))
+}
+
+impl Config {
+    pub fn default_path() -> Option<PathBuf> {
+        let config_home = xdg_dir("XDG_CONFIG_HOME", ".config")?;
+
+        Some(config_home.join("player_watcher").join("config.toml"))
+    }
+
+    /// Load config from `path`, or from the default location if `None`. Missing file means
+    /// default config.
+    pub fn load(path: Option<&Path>) -> Result<Self, Box<dyn Error>> {
+        let path = match path {
+            Some(path) => path.to_owned(),
+            None => match Self::default_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Self::default()),
+            },
+        };
+
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        Self::parse(&text).map_err(|e| format!("invalid config {}: {}", path.display(), e).into())
+    }
+
+    fn parse(text: &str) -> Result<Self, Box<dyn Error>> {
+        let raw: RawConfig = toml::from_str(text)?;
+
+        Ok(Self {
+            ignore: raw
+                .ignore
+                .iter()
+                .map(|p| compile(p))
+                .collect::<Result<_, _>>()?,
+            priority: raw
+                .priority
+                .iter()
+                .map(|p| compile(p))
+                .collect::<Result<_, _>>()?,
+            players: raw
+                .players
+                .into_iter()
+                .map(|(p, o)| Ok((compile(&p)?, o)))
+                .collect::<Result<_, Box<dyn Error>>>()?,
+        })
+    }
+
+    pub fn rules_for(&self, name: &str) -> PlayerRules {
+        let mut rules = PlayerRules {
+            ignored: self.ignore.iter().any(|p| matches(p, name)),
+            ..Default::default()
+        };
+
+        // earlier entries beat later ones, unlisted players have the lowest priority
+        if let Some(idx) = self.priority.iter().position(|p| matches(p, name)) {
+            rules.priority = (self.priority.len() - idx) as i32;
+        }
+
+        for (pattern, player) in &self.players {
+            if matches(pattern, name) {
+                rules.ignored = player.ignore.unwrap_or(rules.ignored);
+                rules.priority = player.priority.unwrap_or(rules.priority);
+                rules.auto_activate = player.auto_activate.unwrap_or(rules.auto_activate);
+            }
+        }
+
+        rules
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIREFOX: &str = "org.mpris.MediaPlayer2.firefox.instance_1_45";
+    const SPOTIFY: &str = "org.mpris.MediaPlayer2.spotify";
+    const KDECONNECT: &str = "org.mpris.MediaPlayer2.kdeconnect.mpris_000001";
+
+    #[test]
+    fn matches_full_name_short_name_and_identity() {
+        let config = Config::parse(
+            r#"
+            ignore = ["*.kdeconnect*"]
+            priority = ["org.mpris.MediaPlayer2.spotify", "firefox.instance_1_45", "firefox"]
+            "#,
+        )
+        .unwrap();
+
+        assert!(config.rules_for(KDECONNECT).ignored);
+        assert!(!config.rules_for(SPOTIFY).ignored);
+
+        assert_eq!(config.rules_for(SPOTIFY).priority, 3);
+        // the first matching entry counts
+        assert_eq!(config.rules_for(FIREFOX).priority, 2);
+        assert_eq!(
+            config
+                .rules_for("org.mpris.MediaPlayer2.firefox.instance_2_3")
+                .priority,
+            1
+        );
+        assert_eq!(
+            config.rules_for("org.mpris.MediaPlayer2.mpv"),
+            PlayerRules::default()
+        );
+    }
+
+    #[test]
+    fn players_override_lists() {
+        let config = Config::parse(
+            r#"
+            ignore = ["*"]
+            priority = ["spotify"]
+
+            [players.spotify]
+            ignore = false
+            priority = -1
+
+            [players.firefox]
+            auto_activate = false
+            "#,
+        )
+        .unwrap();
+
+        let spotify = config.rules_for(SPOTIFY);
+        assert!(!spotify.ignored);
+        assert_eq!(spotify.priority, -1);
+        assert!(spotify.auto_activate);
+
+        let firefox = config.rules_for(FIREFOX);
+        assert!(firefox.ignored);
+        assert!(!firefox.auto_activate);
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        assert!(Config::parse("ignore = [\"[\"]").is_err());
+        assert!(Config::parse("unknown = 1").is_err());
+        assert!(Config::parse("[players.mpv]\npriority = \"high\"").is_err());
+    }
+}
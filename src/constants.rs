@@ -1,12 +1,22 @@
 pub const WELL_KNOWN_NAME: &str = "org.mpris.player_watcher";
 pub const WELL_KNOWN_PATH: &str = "/org/mpris/player_watcher";
 pub const ACTIVE_PLAYER_PROPERTY: &str = "ActivePlayer";
+pub const PLAYERS_PROPERTY: &str = "Players";
+pub const PINNED_PROPERTY: &str = "Pinned";
 pub const SHIFT_METHOD: &str = "Shift";
 pub const UNSHIFT_METHOD: &str = "Unshift";
+pub const SELECT_METHOD: &str = "Select";
+pub const PIN_METHOD: &str = "Pin";
+pub const UNPIN_METHOD: &str = "Unpin";
+pub const RELOAD_METHOD: &str = "Reload";
+pub const NO_PLAYERS_ERROR: &str = "org.mpris.player_watcher.Error.NoPlayers";
+pub const NO_MATCHING_PLAYER_ERROR: &str = "org.mpris.player_watcher.Error.NoMatchingPlayer";
 
 pub const DBUS: &str = "org.freedesktop.DBus";
+pub const SERVICE_UNKNOWN_ERROR: &str = "org.freedesktop.DBus.Error.ServiceUnknown";
 pub const MPRIS_PREFIX: &str = "org.mpris.MediaPlayer2.";
 pub const MPRIS_PLAYER_ITF: &str = "org.mpris.MediaPlayer2.Player";
 pub const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
+pub const SEEKED: &str = "Seeked";
 pub const PROPERTIES: &str = "org.freedesktop.DBus.Properties";
 pub const PROPERTIES_CHANGED: &str = "PropertiesChanged";
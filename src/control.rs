@@ -1,21 +1,338 @@
+use crate::bus;
 use crate::constants::{MPRIS_PATH, MPRIS_PLAYER_ITF};
 use crate::get::get_active_player_impl;
-use dbus::nonblock::Proxy;
-use dbus_tokio::connection;
+use clap::ValueEnum;
+use dbus::Path;
+use dbus::arg::{PropMap, RefArg};
+use dbus::nonblock::stdintf::org_freedesktop_dbus::Properties;
+use dbus::nonblock::{Proxy, SyncConnection};
+use std::error::Error;
+use std::sync::Arc;
 use std::time::Duration;
 
-pub async fn control(command: &str) -> Result<(), Box<dbus::Error>> {
-    let (resource, connection) = connection::new_session_sync()?;
-    tokio::spawn(async {
-        let err = resource.await;
-        panic!("Lost connection to D-Bus: {}", err);
-    });
+const MICROSECONDS: f64 = 1_000_000.0;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Volume {
+    Absolute(f64),
+    Relative(f64),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum Switch {
+    On,
+    Off,
+    Toggle,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum LoopStatus {
+    None,
+    Track,
+    Playlist,
+}
+
+impl LoopStatus {
+    fn as_mpris(&self) -> &'static str {
+        match self {
+            LoopStatus::None => "None",
+            LoopStatus::Track => "Track",
+            LoopStatus::Playlist => "Playlist",
+        }
+    }
+}
+
+/// Parse time as microseconds: `90`, `90s`, `1500ms`, `1:30` or `1:02:03`, optionally signed.
+pub fn parse_time(s: &str) -> Result<i64, String> {
+    let (sign, unsigned) = match s.as_bytes().first() {
+        Some(b'-') => (-1.0, &s[1..]),
+        Some(b'+') => (1.0, &s[1..]),
+        _ => (1.0, s),
+    };
+    let invalid = || {
+        format!(
+            "invalid time {:?}, expected e.g. 90, 90s, 1500ms or 1:30",
+            s
+        )
+    };
+    // the only sign is the leading one, components are never negative
+    let number = |part: &str| match part.as_bytes().first() {
+        Some(b'-' | b'+') => Err(invalid()),
+        _ => part.parse::<f64>().map_err(|_| invalid()),
+    };
+
+    let seconds = if let Some(ms) = unsigned.strip_suffix("ms") {
+        number(ms)? / 1000.0
+    } else if unsigned.contains(':') {
+        let parts = unsigned.split(':').collect::<Vec<_>>();
+        if parts.len() > 3 {
+            return Err(invalid());
+        }
+        let mut seconds = 0.0;
+        for part in parts {
+            seconds = seconds * 60.0 + number(part)?;
+        }
+        seconds
+    } else {
+        number(unsigned.strip_suffix('s').unwrap_or(unsigned))?
+    };
+
+    if !seconds.is_finite() || seconds < 0.0 {
+        return Err(invalid());
+    }
+    Ok((sign * seconds * MICROSECONDS) as i64)
+}
+
+/// Parse absolute position, see `parse_time`.
+pub fn parse_position(s: &str) -> Result<i64, String> {
+    if s.starts_with('+') || s.starts_with('-') {
+        return Err(format!("position {:?} must be absolute", s));
+    }
+    parse_time(s)
+}
+
+/// Parse positive interval, see `parse_time`.
+pub fn parse_interval(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("interval {:?} must be positive, e.g. 1s or 500ms", s);
+    if s.starts_with('+') || s.starts_with('-') {
+        return Err(invalid());
+    }
+
+    match parse_time(s)? {
+        0 => Err(invalid()),
+        microseconds => Ok(Duration::from_micros(microseconds as u64)),
+    }
+}
+
+/// Parse volume: `0.5` or `50%` sets it, `+0.1`, `-5%` changes it.
+pub fn parse_volume(s: &str) -> Result<Volume, String> {
+    let invalid = || {
+        format!(
+            "invalid volume {:?}, expected e.g. 0.5, 50%, +0.1 or -5%",
+            s
+        )
+    };
+
+    let value = match s.strip_suffix('%') {
+        Some(percent) => percent.parse::<f64>().map_err(|_| invalid())? / 100.0,
+        None => s.parse::<f64>().map_err(|_| invalid())?,
+    };
+    if !value.is_finite() {
+        return Err(invalid());
+    }
+
+    if s.starts_with('+') || s.starts_with('-') {
+        Ok(Volume::Relative(value))
+    } else {
+        Ok(Volume::Absolute(value))
+    }
+}
+
+async fn active_player_proxy() -> Result<Proxy<'static, Arc<SyncConnection>>, Box<dyn Error>> {
+    let connection = bus::connect_once()?;
 
     let (player, _) = get_active_player_impl(connection.clone()).await?;
+    if player.is_empty() {
+        return Err("no active player".into());
+    }
 
-    let proxy = Proxy::new(&player, MPRIS_PATH, Duration::from_secs(5), connection);
+    Ok(Proxy::new(
+        player,
+        MPRIS_PATH,
+        Duration::from_secs(5),
+        connection,
+    ))
+}
+
+/// Fail unless the player reports boolean property `capability` as true.
+async fn require(
+    proxy: &Proxy<'_, Arc<SyncConnection>>,
+    capability: &str,
+    action: &str,
+) -> Result<(), Box<dyn Error>> {
+    if proxy.get::<bool>(MPRIS_PLAYER_ITF, capability).await? {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} does not support {} ({} is false)",
+            proxy.destination, action, capability
+        )
+        .into())
+    }
+}
+
+fn required_capability(command: &str) -> &'static str {
+    match command {
+        "Play" => "CanPlay",
+        "Pause" | "PlayPause" => "CanPause",
+        "Next" => "CanGoNext",
+        "Previous" => "CanGoPrevious",
+        _ => "CanControl",
+    }
+}
+
+pub async fn control(command: &str) -> Result<(), Box<dyn Error>> {
+    let proxy = active_player_proxy().await?;
+    require(&proxy, required_capability(command), command).await?;
 
     let _prop: () = proxy.method_call(MPRIS_PLAYER_ITF, command, ()).await?;
 
     Ok(())
 }
+
+pub async fn seek(offset: i64) -> Result<(), Box<dyn Error>> {
+    let proxy = active_player_proxy().await?;
+    require(&proxy, "CanSeek", "seeking").await?;
+
+    let (): () = proxy
+        .method_call(MPRIS_PLAYER_ITF, "Seek", (offset,))
+        .await?;
+
+    Ok(())
+}
+
+pub async fn set_position(position: i64) -> Result<(), Box<dyn Error>> {
+    let proxy = active_player_proxy().await?;
+    require(&proxy, "CanSeek", "changing position").await?;
+
+    let metadata = proxy.get::<PropMap>(MPRIS_PLAYER_ITF, "Metadata").await?;
+    let track_id = metadata
+        .get("mpris:trackid")
+        .and_then(|v| v.as_str())
+        .and_then(|v| Path::new(v.to_owned()).ok())
+        .ok_or("player does not report mpris:trackid of the current track")?;
+
+    let (): () = proxy
+        .method_call(MPRIS_PLAYER_ITF, "SetPosition", (track_id, position))
+        .await?;
+
+    Ok(())
+}
+
+pub async fn set_volume(volume: Volume) -> Result<(), Box<dyn Error>> {
+    let proxy = active_player_proxy().await?;
+    require(&proxy, "CanControl", "changing volume").await?;
+
+    let volume = match volume {
+        Volume::Absolute(volume) => volume,
+        Volume::Relative(change) => proxy.get::<f64>(MPRIS_PLAYER_ITF, "Volume").await? + change,
+    };
+
+    proxy
+        .set(MPRIS_PLAYER_ITF, "Volume", volume.max(0.0))
+        .await?;
+
+    Ok(())
+}
+
+pub async fn set_shuffle(shuffle: Switch) -> Result<(), Box<dyn Error>> {
+    let proxy = active_player_proxy().await?;
+    require(&proxy, "CanControl", "changing shuffle").await?;
+
+    let shuffle = match shuffle {
+        Switch::On => true,
+        Switch::Off => false,
+        Switch::Toggle => !proxy.get::<bool>(MPRIS_PLAYER_ITF, "Shuffle").await?,
+    };
+
+    proxy.set(MPRIS_PLAYER_ITF, "Shuffle", shuffle).await?;
+
+    Ok(())
+}
+
+pub async fn set_loop(status: LoopStatus) -> Result<(), Box<dyn Error>> {
+    let proxy = active_player_proxy().await?;
+    require(&proxy, "CanControl", "changing loop status").await?;
+
+    proxy
+        .set(
+            MPRIS_PLAYER_ITF,
+            "LoopStatus",
+            status.as_mpris().to_string(),
+        )
+        .await?;
+
+    Ok(())
+}
+
+pub async fn open_uri(uri: String) -> Result<(), Box<dyn Error>> {
+    let proxy = active_player_proxy().await?;
+
+    let (): () = proxy
+        .method_call(MPRIS_PLAYER_ITF, "OpenUri", (uri,))
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_time() {
+        assert_eq!(parse_time("90"), Ok(90_000_000));
+        assert_eq!(parse_time("90s"), Ok(90_000_000));
+        assert_eq!(parse_time("1500ms"), Ok(1_500_000));
+        assert_eq!(parse_time("0.5"), Ok(500_000));
+        assert_eq!(parse_time("1:30"), Ok(90_000_000));
+        assert_eq!(parse_time("1:02:03"), Ok(3_723_000_000));
+        assert_eq!(parse_time("+10s"), Ok(10_000_000));
+        assert_eq!(parse_time("-1:00"), Ok(-60_000_000));
+    }
+
+    #[test]
+    fn rejects_invalid_time() {
+        for time in [
+            "", "s", "ms", "abc", "1:2:3:4", "1::30", "1:-30", "1:+30", "--5", "-+5", "+-5ms",
+            "inf", "NaN", "10m",
+        ] {
+            assert!(parse_time(time).is_err(), "{:?} accepted", time);
+        }
+    }
+
+    #[test]
+    fn parses_absolute_position_only() {
+        assert_eq!(parse_position("1:23"), Ok(83_000_000));
+        assert_eq!(
+            parse_position("+5s"),
+            Err("position \"+5s\" must be absolute".to_owned())
+        );
+        assert!(parse_position("-5s").is_err());
+    }
+
+    #[test]
+    fn parses_positive_interval() {
+        assert_eq!(parse_interval("1s"), Ok(Duration::from_secs(1)));
+        assert_eq!(parse_interval("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(
+            parse_interval("-1s"),
+            Err("interval \"-1s\" must be positive, e.g. 1s or 500ms".to_owned())
+        );
+        assert!(parse_interval("+1s").is_err());
+        assert!(parse_interval("0").is_err());
+        assert!(parse_interval("0ms").is_err());
+    }
+
+    #[test]
+    fn parses_volume() {
+        assert_eq!(parse_volume("0.5"), Ok(Volume::Absolute(0.5)));
+        assert_eq!(parse_volume("50%"), Ok(Volume::Absolute(0.5)));
+        assert_eq!(parse_volume("+0.1"), Ok(Volume::Relative(0.1)));
+        assert_eq!(parse_volume("-5%"), Ok(Volume::Relative(-0.05)));
+
+        for volume in ["", "%", "half", "5%%", "inf", "-NaN%"] {
+            assert!(parse_volume(volume).is_err(), "{:?} accepted", volume);
+        }
+    }
+
+    #[test]
+    fn requires_capability_of_command() {
+        assert_eq!(required_capability("Play"), "CanPlay");
+        assert_eq!(required_capability("Pause"), "CanPause");
+        assert_eq!(required_capability("PlayPause"), "CanPause");
+        assert_eq!(required_capability("Next"), "CanGoNext");
+        assert_eq!(required_capability("Previous"), "CanGoPrevious");
+        assert_eq!(required_capability("Stop"), "CanControl");
+    }
+}
@@ -1,23 +1,37 @@
+use crate::bus::{self, Backoff, Disconnected};
+use crate::config::Config;
 use crate::constants::{
-    ACTIVE_PLAYER_PROPERTY, DBUS, MPRIS_PATH, MPRIS_PLAYER_ITF, MPRIS_PREFIX, PROPERTIES,
-    PROPERTIES_CHANGED, SHIFT_METHOD, UNSHIFT_METHOD, WELL_KNOWN_NAME, WELL_KNOWN_PATH,
+    ACTIVE_PLAYER_PROPERTY, DBUS, MPRIS_PATH, MPRIS_PLAYER_ITF, MPRIS_PREFIX,
+    NO_MATCHING_PLAYER_ERROR, NO_PLAYERS_ERROR, PIN_METHOD, PINNED_PROPERTY, PLAYERS_PROPERTY,
+    PROPERTIES, PROPERTIES_CHANGED, RELOAD_METHOD, SELECT_METHOD, SERVICE_UNKNOWN_ERROR,
+    SHIFT_METHOD, UNPIN_METHOD, UNSHIFT_METHOD, WELL_KNOWN_NAME, WELL_KNOWN_PATH,
 };
-use crate::players::Players;
-use crate::players_queue::PlayersQueue;
+use crate::state::State;
+use crate::tracker::{Event, Reply, Snapshot, Tracker};
 use dbus::Message;
-use dbus::arg::{RefArg, Variant, prop_cast};
-use dbus::channel::Sender;
+use dbus::MethodErr;
+use dbus::arg::{PropMap, RefArg, Variant, prop_cast};
+use dbus::channel::{MatchingReceiver, Sender, Token};
 use dbus::message::MatchRule;
 use dbus::nonblock::stdintf::org_freedesktop_dbus::{
     Properties, PropertiesPropertiesChanged, RequestNameReply,
 };
 use dbus::nonblock::{MsgMatch, Proxy, SyncConnection};
-use dbus_tokio::connection;
+use dbus_crossroads::{Crossroads, IfaceBuilder};
 use std::collections::HashMap;
-use std::ops::Deref;
-use std::sync::{Arc, RwLock};
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::{Arc, PoisonError, RwLock};
 use std::time::Duration;
 use tokio::signal;
+use tokio::signal::unix::{Signal, SignalKind, signal};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+use tokio::sync::{oneshot, watch};
+
+/// Players which don't answer in time are tried again, then added with unknown status.
+const STATUS_TIMEOUT: Duration = Duration::from_secs(2);
+const STATUS_ATTEMPTS: u32 = 3;
+const STATUS_RETRY_DELAY: Duration = Duration::from_millis(250);
 
 fn is_player(name: &str) -> bool {
     name.starts_with(MPRIS_PREFIX)
@@ -26,53 +40,178 @@ fn is_player(name: &str) -> bool {
 #[derive(Clone)]
 pub struct Daemon {
     connection: Arc<SyncConnection>,
-    players: Arc<RwLock<Players>>,
-    queue: Arc<RwLock<PlayersQueue>>,
+    /// all changes of players go to the tracker owned by `serve_until_stopped`
+    events: UnboundedSender<Event>,
+    snapshot: Arc<watch::Sender<Snapshot>>,
+    config: Arc<RwLock<Config>>,
+    config_path: Option<PathBuf>,
+    /// where order of players is saved, `None` if no location is known
+    state_path: Option<PathBuf>,
 }
 
-impl Daemon {
-    pub async fn new() -> Result<Self, dbus::Error> {
-        let (resource, connection) = connection::new_session_sync()?;
+/// Serve until interrupted, reconnecting to the bus whenever the connection is lost.
+pub async fn run_daemon(config_path: Option<PathBuf>) -> Result<(), Box<dyn Error>> {
+    let config = Arc::new(RwLock::new(Config::load(config_path.as_deref())?));
+    let state_path = State::default_path();
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut backoff = Backoff::default();
+    let mut connected_once = false;
+
+    loop {
+        let (connection, disconnected) = match bus::connect() {
+            Ok(connection) => connection,
+            Err(err) if connected_once => {
+                eprintln!("cannot connect to D-Bus: {}", err);
+                tokio::select! {
+                    _ = backoff.wait() => continue,
+                    _ = signal::ctrl_c() => return Ok(()),
+                }
+            }
+            Err(err) => return Err(err.into()),
+        };
 
-        tokio::spawn(async {
-            let err = resource.await;
-            panic!("Lost connection to D-Bus: {}", err);
-        });
+        let (mut daemon, events) = Daemon::new(
+            connection.clone(),
+            config.clone(),
+            config_path.clone(),
+            state_path.clone(),
+        );
+
+        match daemon.run(events, disconnected, &mut hangup).await {
+            Ok(true) => return Ok(()),
+            Ok(false) => backoff.reset(),
+            // e.g. another instance is running, unless it only took the name while reconnecting
+            Err(err) if !connected_once && bus::is_connected(&connection) => return Err(err),
+            Err(err) => eprintln!("{}", err),
+        }
+        connected_once = true;
+        eprintln!("reconnecting to D-Bus");
+
+        tokio::select! {
+            _ = backoff.wait() => {}
+            _ = signal::ctrl_c() => return Ok(()),
+        }
+    }
+}
+
+impl Daemon {
+    fn new(
+        connection: Arc<SyncConnection>,
+        config: Arc<RwLock<Config>>,
+        config_path: Option<PathBuf>,
+        state_path: Option<PathBuf>,
+    ) -> (Self, UnboundedReceiver<Event>) {
+        let (events, receiver) = unbounded_channel();
+        let daemon = Self {
+            connection,
+            events,
+            snapshot: Arc::new(watch::Sender::new(Snapshot::default())),
+            config,
+            config_path,
+            state_path,
+        };
+        (daemon, receiver)
+    }
 
-        let reply = connection
+    async fn request_name(&self) -> Result<(), Box<dyn Error>> {
+        let reply = self
+            .connection
             .request_name(WELL_KNOWN_NAME, false, false, true)
             .await?;
 
         if reply != RequestNameReply::PrimaryOwner {
-            panic!("Already running");
+            return Err(format!("{} is already running", WELL_KNOWN_NAME).into());
         }
 
-        let players = Arc::new(RwLock::new(Players::default()));
-        let queue = Arc::new(RwLock::new(PlayersQueue::default()));
+        Ok(())
+    }
 
-        Ok(Self {
-            connection,
-            players,
-            queue,
-        })
+    /// Serve on the current connection. Return whether interrupted by user, `false` means the
+    /// connection was lost.
+    async fn run(
+        &mut self,
+        events: UnboundedReceiver<Event>,
+        mut disconnected: Disconnected,
+        hangup: &mut Signal,
+    ) -> Result<bool, Box<dyn Error>> {
+        // clients query the daemon as soon as the name appears, so it must be served already
+        let server = self.serve();
+        let mut listens = Vec::new();
+        let result = self
+            .serve_until_stopped(events, &mut listens, &mut disconnected, hangup)
+            .await;
+
+        // callbacks keep clones of the daemon, so they must be dropped to close the connection
+        bus::stop_listening(&self.connection, listens).await;
+        self.connection.stop_receive(server);
+
+        result
     }
 
-    pub async fn run(&mut self) -> Result<(), dbus::Error> {
-        let m0 = self.listen_for_player_changes().await?;
+    /// Added matches are pushed to `listens`, to be removed however this ends.
+    async fn serve_until_stopped(
+        &mut self,
+        mut events: UnboundedReceiver<Event>,
+        listens: &mut Vec<MsgMatch>,
+        disconnected: &mut Disconnected,
+        hangup: &mut Signal,
+    ) -> Result<bool, Box<dyn Error>> {
+        self.request_name().await?;
+
+        let mut state = self.load_state();
+        let mut tracker = Tracker::new(self.config.clone());
+        tracker.restore(state.clone());
+        let (saved_state, states) = watch::channel(state.clone());
+        let saver = self
+            .state_path
+            .clone()
+            .map(|path| tokio::spawn(save_states(path, states)));
+
+        listens.push(self.listen_for_player_changes().await?);
         self.find_existing(self.connection.clone()).await?;
+        self.send(Event::Restored);
+        listens.push(self.listen_for_status_changes().await?);
+
+        let interrupted = loop {
+            tokio::select! {
+                Some(event) = events.recv() => {
+                    if let Some(channel) = tracker.handle(event) {
+                        let daemon = self.clone();
+                        tokio::spawn(async move { daemon.find_channel(channel).await });
+                    }
 
-        let m1 = self.listen_for_property_gets().await?;
-        let m2 = self.listen_for_methods().await?;
-        let m3 = self.listen_for_status_changes().await?;
-
-        signal::ctrl_c().await.expect("failed to listen for event");
+                    let snapshot = tracker.snapshot();
+                    if state.update(&snapshot) {
+                        saved_state.send_replace(state.clone());
+                    }
+                    self.publish(snapshot);
+                }
+                result = signal::ctrl_c() => {
+                    result.expect("failed to listen for event");
+                    break true;
+                }
+                _ = hangup.recv() => {
+                    let mut daemon = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = daemon.reload().await {
+                            eprintln!("reload failed: {}", err);
+                        }
+                    });
+                }
+                reason = &mut *disconnected => {
+                    eprintln!("lost connection to D-Bus: {}", reason.unwrap_or_default());
+                    break false;
+                }
+            }
+        };
 
-        self.connection.remove_match(m3.token()).await?;
-        self.connection.remove_match(m2.token()).await?;
-        self.connection.remove_match(m1.token()).await?;
-        self.connection.remove_match(m0.token()).await?;
+        // wait for the latest order to be saved
+        drop(saved_state);
+        if let Some(saver) = saver {
+            let _ = saver.await;
+        }
 
-        Ok(())
+        Ok(interrupted)
     }
 
     async fn find_existing(&mut self, connection: Arc<SyncConnection>) -> Result<(), dbus::Error> {
@@ -81,45 +220,158 @@ impl Daemon {
         let (names,): (Vec<String>,) = proxy.method_call(DBUS, "ListNames", ()).await?;
 
         for name in names {
-            if is_player(&name) {
-                let (owners,): (Vec<String>,) = proxy
-                    .method_call(DBUS, "ListQueuedOwners", (&name,))
-                    .await?;
-                println!("old player {:?}", name);
-                self.add(name, owners.into_iter().map(Into::into).collect())
-                    .await?;
+            if !self.is_tracked(&name) || self.snapshot.borrow().contains(&name) {
+                continue;
             }
+            let owners = match proxy
+                .method_call::<(Vec<String>,), _, _, _>(DBUS, "ListQueuedOwners", (&name,))
+                .await
+            {
+                Ok((owners,)) => owners,
+                Err(err) => {
+                    eprintln!("cannot list owners of {:?}: {}", name, err);
+                    continue;
+                }
+            };
+            println!("old player {:?}", name);
+
+            let name: Arc<str> = name.into();
+            let channels = owners.into_iter().map(Into::into).collect();
+            self.send(Event::Add {
+                name: name.clone(),
+                channels,
+            });
+
+            // read in background, so a player which doesn't answer doesn't hold up others
+            let daemon = self.clone();
+            tokio::spawn(async move { daemon.check_status(name).await });
         }
 
         Ok(())
     }
 
-    async fn add(&mut self, name: String, channels: Vec<Arc<str>>) -> Result<(), dbus::Error> {
-        let name: Arc<str> = name.into();
-        let playing = self.check_if_playing(&name).await?;
+    fn load_state(&self) -> State {
+        let Some(path) = &self.state_path else {
+            return State::default();
+        };
+
+        State::load(path).unwrap_or_else(|err| {
+            eprintln!("cannot restore order of players: {}", err);
+            State::default()
+        })
+    }
+
+    fn is_tracked(&self, name: &str) -> bool {
+        is_player(name)
+            && !self
+                .config
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .rules_for(name)
+                .ignored
+    }
 
-        self.players.write().unwrap().add(name.clone(), channels);
-        self.queue.write().unwrap().add_player(name, playing);
-        self.notify_of_new_active();
+    fn send(&self, event: Event) {
+        // events sent while the daemon is stopping are dropped
+        let _ = self.events.send(event);
+    }
+
+    /// Send request to the tracker and wait for its reply.
+    async fn request<T>(&self, event: impl FnOnce(Reply<T>) -> Event) -> Result<T, MethodErr> {
+        let (reply, receiver) = oneshot::channel();
+        self.send(event(reply));
+        receiver
+            .await
+            .map_err(|_| MethodErr::failed("daemon is stopping"))
+    }
+
+    /// Publish new state to clients and notify them if it changed.
+    fn publish(&self, snapshot: Snapshot) {
+        if *self.snapshot.borrow() == snapshot {
+            return;
+        }
+        let old = self.snapshot.send_replace(snapshot.clone());
+        self.notify_of_changes(&old, &snapshot);
+    }
+
+    /// Re-read config and apply it to already tracked players, then pick up players which are
+    /// no longer ignored.
+    async fn reload(&mut self) -> Result<(), MethodErr> {
+        let config =
+            Config::load(self.config_path.as_deref()).map_err(|err| MethodErr::failed(&err))?;
+        *self.config.write().unwrap_or_else(PoisonError::into_inner) = config;
+        self.send(Event::Reload);
+
+        self.find_existing(self.connection.clone()).await?;
 
         Ok(())
     }
 
-    async fn remove(&mut self, name: &str) -> Result<(), dbus::Error> {
-        self.players.write().unwrap().remove(name);
-        self.queue.write().unwrap().remove_player(name);
-        self.notify_of_new_active();
+    /// Read status of player which appeared or got a new owner.
+    async fn check_status(&self, name: Arc<str>) {
+        match self.probe_status(&name).await {
+            Ok(Some(playing)) => self.send(Event::PlayerStatus { name, playing }),
+            Ok(None) => {}
+            Err(err) => eprintln!("cannot read status of {:?}: {}", name, err),
+        }
+    }
+
+    /// Re-read all owners of `name`, including the queued ones.
+    async fn refresh_channels(&self, name: &Arc<str>) -> Result<(), dbus::Error> {
+        let proxy = Proxy::new(DBUS, "/", Duration::from_secs(5), self.connection.clone());
+        let (owners,): (Vec<String>,) = proxy
+            .method_call(DBUS, "ListQueuedOwners", (name.as_ref(),))
+            .await?;
+
+        self.send(Event::SetChannels {
+            name: name.clone(),
+            channels: owners.into_iter().map(Into::into).collect(),
+        });
 
         Ok(())
     }
 
+    /// Look for the player owning `channel`, which may have been opened after the player was
+    /// added.
+    async fn find_channel(&self, channel: Arc<str>) {
+        let names = self
+            .snapshot
+            .borrow()
+            .players
+            .iter()
+            .map(|(name, _, _)| name.clone())
+            .collect::<Vec<_>>();
+        for name in names {
+            if let Err(err) = self.refresh_channels(&name).await {
+                eprintln!("cannot list owners of {:?}: {}", name, err);
+            }
+        }
+
+        self.send(Event::LookedUp(channel));
+    }
+
+    /// Check whether `player` is playing, retrying when it fails to answer. Return `None` if the
+    /// status is still unknown, error if the player left the bus.
+    async fn probe_status(&self, player: &str) -> Result<Option<bool>, dbus::Error> {
+        for attempt in 1..=STATUS_ATTEMPTS {
+            match self.check_if_playing(player).await {
+                Ok(playing) => return Ok(Some(playing)),
+                Err(err) if err.name() == Some(SERVICE_UNKNOWN_ERROR) => return Err(err),
+                Err(err) if attempt == STATUS_ATTEMPTS => {
+                    eprintln!(
+                        "cannot read status of {:?}, assuming it's not playing: {}",
+                        player, err
+                    );
+                }
+                Err(_) => tokio::time::sleep(STATUS_RETRY_DELAY).await,
+            }
+        }
+
+        Ok(None)
+    }
+
     async fn check_if_playing(&self, player: &str) -> Result<bool, dbus::Error> {
-        let proxy = Proxy::new(
-            player,
-            MPRIS_PATH,
-            Duration::from_secs(5),
-            self.connection.clone(),
-        );
+        let proxy = Proxy::new(player, MPRIS_PATH, STATUS_TIMEOUT, self.connection.clone());
 
         let props = proxy
             .get::<String>(MPRIS_PLAYER_ITF, "PlaybackStatus")
@@ -137,17 +389,30 @@ impl Daemon {
         let mr = MatchRule::new_signal(DBUS, "NameOwnerChanged");
         let m = self.connection.add_match(mr).await?.cb(
             move |_, (name, old_owner, new_owner): (String, String, String)| {
-                if is_player(&name) {
-                    assert_ne!(old_owner.is_empty(), new_owner.is_empty());
+                if name.starts_with(':') && new_owner.is_empty() {
+                    daemon.send(Event::CloseChannel(name.into()));
+                } else if daemon.is_tracked(&name) {
                     println!("new player {:?}", new_owner);
 
-                    let mut clone = daemon.clone();
+                    let name: Arc<str> = name.into();
+                    if new_owner.is_empty() {
+                        daemon.send(Event::Remove(name));
+                        return true;
+                    }
+                    daemon.send(Event::Add {
+                        name: name.clone(),
+                        channels: vec![new_owner.into()],
+                    });
+
+                    let daemon = daemon.clone();
                     tokio::spawn(async move {
-                        if old_owner.is_empty() {
-                            clone.add(name, vec![new_owner.into()]).await.unwrap();
-                        } else {
-                            clone.remove(&name).await.unwrap();
+                        // name was handed over, other owners may be queued for it
+                        if !old_owner.is_empty()
+                            && let Err(err) = daemon.refresh_channels(&name).await
+                        {
+                            eprintln!("cannot list owners of {:?}: {}", name, err);
                         }
+                        daemon.check_status(name).await;
                     });
                 }
                 true
@@ -157,69 +422,88 @@ impl Daemon {
         Ok(m)
     }
 
-    async fn listen_for_property_gets(&self) -> Result<MsgMatch, dbus::Error> {
-        let daemon = self.clone();
-        let mr = MatchRule::new_method_call()
-            .with_path(WELL_KNOWN_PATH)
-            .with_interface(PROPERTIES)
-            .with_member("Get");
-
-        let m = self
-            .connection
-            .add_match(mr)
-            .await?
-            .cb(move |req, (_, name): (String, String)| {
-                if name == ACTIVE_PLAYER_PROPERTY {
-                    let players = daemon.players.read().unwrap();
-                    let queue = daemon.queue.read().unwrap();
-
-                    let resp = queue
-                        .get_active()
-                        .as_ref()
-                        .map(ToString::to_string)
-                        .unwrap_or_default();
-                    let channels = players.get_channels(resp.clone().into());
-
-                    let msg = if let Some(channels) = channels {
-                        Message::new_method_return(&req).unwrap().append1(Variant((
-                            resp,
-                            Vec::from_iter(channels.iter().map(|s| s.to_string())),
-                        )))
-                    } else {
-                        Message::new_method_return(&req)
-                            .unwrap()
-                            .append1(Variant((resp, Vec::<String>::new())))
-                    };
-
-                    daemon.connection.send(msg).unwrap();
+    fn serve(&self) -> Token {
+        let mut cr = Crossroads::new();
+        let iface = cr.register(WELL_KNOWN_NAME, |b: &mut IfaceBuilder<Daemon>| {
+            b.property(ACTIVE_PLAYER_PROPERTY)
+                .get(|_, daemon| Ok(active_player(&daemon.snapshot.borrow())));
+            b.property(PLAYERS_PROPERTY)
+                .get(|_, daemon| Ok(all_players(&daemon.snapshot.borrow())));
+            b.property(PINNED_PROPERTY)
+                .get(|_, daemon| Ok(daemon.snapshot.borrow().pinned));
+            b.method_with_cr_async(SHIFT_METHOD, (), ("player",), |mut ctx, cr, ()| {
+                let daemon = cr.data_mut::<Daemon>(ctx.path()).unwrap().clone();
+                async move {
+                    let active = daemon.request(Event::Shift).await;
+                    ctx.reply(active.and_then(reply_with_player))
                 }
-
-                true
             });
-
-        Ok(m)
-    }
-
-    async fn listen_for_methods(&self) -> Result<MsgMatch, dbus::Error> {
-        let daemon = self.clone();
-        let mr = MatchRule::new_method_call()
-            .with_path(WELL_KNOWN_PATH)
-            .with_interface(WELL_KNOWN_NAME);
-
-        let m = self.connection.add_match(mr).await?.cb(move |req, (): ()| {
-            if let Some(command) = req.member() {
-                if command.deref() == SHIFT_METHOD {
-                    daemon.queue.write().unwrap().shift();
-                } else if command.deref() == UNSHIFT_METHOD {
-                    daemon.queue.write().unwrap().unshift();
+            b.method_with_cr_async(UNSHIFT_METHOD, (), ("player",), |mut ctx, cr, ()| {
+                let daemon = cr.data_mut::<Daemon>(ctx.path()).unwrap().clone();
+                async move {
+                    let active = daemon.request(Event::Unshift).await;
+                    ctx.reply(active.and_then(reply_with_player))
                 }
-                daemon.notify_of_new_active();
-            }
-
-            true
+            });
+            b.method_with_cr_async(
+                SELECT_METHOD,
+                ("name",),
+                ("player",),
+                |mut ctx, cr, (name,): (String,)| {
+                    let daemon = cr.data_mut::<Daemon>(ctx.path()).unwrap().clone();
+                    async move {
+                        let selected = daemon
+                            .request(|reply| Event::Select(name.clone(), reply))
+                            .await;
+                        let result = match selected {
+                            Ok(Some(active)) => Ok((active.to_string(),)),
+                            Ok(None) => Err(MethodErr::from((
+                                NO_MATCHING_PLAYER_ERROR,
+                                format!("No player matching {:?}", name),
+                            ))),
+                            Err(err) => Err(err),
+                        };
+                        ctx.reply(result)
+                    }
+                },
+            );
+            b.method_with_cr_async(PIN_METHOD, (), ("player",), |mut ctx, cr, ()| {
+                let daemon = cr.data_mut::<Daemon>(ctx.path()).unwrap().clone();
+                async move {
+                    let pinned = daemon.request(Event::Pin).await;
+                    ctx.reply(pinned.and_then(reply_with_player))
+                }
+            });
+            b.method_with_cr_async(UNPIN_METHOD, (), ("player",), |mut ctx, cr, ()| {
+                let daemon = cr.data_mut::<Daemon>(ctx.path()).unwrap().clone();
+                async move {
+                    let active = daemon.request(Event::Unpin).await;
+                    ctx.reply(active.map(|active| (active.unwrap_or_default().to_string(),)))
+                }
+            });
+            b.method_with_cr_async(RELOAD_METHOD, (), (), |mut ctx, cr, ()| {
+                let mut daemon = cr.data_mut::<Daemon>(ctx.path()).unwrap().clone();
+                async move {
+                    let result = daemon.reload().await;
+                    ctx.reply(result)
+                }
+            });
         });
-
-        Ok(m)
+        cr.set_async_support(Some((
+            self.connection.clone(),
+            Box::new(|future| {
+                tokio::spawn(future);
+            }),
+        )));
+        cr.insert(WELL_KNOWN_PATH, &[iface], self.clone());
+
+        self.connection.start_receive(
+            MatchRule::new_method_call(),
+            Box::new(move |msg, conn| {
+                let _ = cr.handle_message(msg, conn);
+                true
+            }),
+        )
     }
 
     async fn listen_for_status_changes(&self) -> Result<MsgMatch, dbus::Error> {
@@ -228,28 +512,17 @@ impl Daemon {
         let mr = MatchRule::new_signal(PROPERTIES, PROPERTIES_CHANGED).with_path(MPRIS_PATH);
         let m = self.connection.add_match(mr).await?.cb(
             move |msg, props: PropertiesPropertiesChanged| {
-                let mut player = Arc::<str>::from("");
-                let mut status = String::new();
-
-                if let Some(sender) = msg.sender() {
-                    if let Some(player_) = daemon.players.read().unwrap().find_by_channel(&sender) {
-                        if let Some(status_) =
-                            prop_cast::<String>(&props.changed_properties, "PlaybackStatus")
-                        {
-                            player = player_.clone();
-                            status = status_.clone();
-                        }
-                    }
-                }
-
-                // must be outside `if let` to prevent deadlock of daemon.players
-                if status == "Playing" {
-                    if daemon.queue.write().unwrap().promote(player) {
-                        daemon.notify_of_new_active();
-                    }
-                } else if daemon.queue.write().unwrap().demote(player) {
-                    daemon.notify_of_new_active();
-                }
+                let Some(playing) = read_status(&props.changed_properties) else {
+                    return true;
+                };
+                let Some(sender) = msg.sender() else {
+                    return true;
+                };
+
+                daemon.send(Event::ChannelStatus {
+                    channel: Arc::from(sender.to_string()),
+                    playing,
+                });
 
                 true
             },
@@ -258,23 +531,119 @@ impl Daemon {
         Ok(m)
     }
 
-    fn notify_of_new_active(&self) {
-        let active = self.queue.read().unwrap().get_active().unwrap_or_default();
+    fn notify_of_changes(&self, old: &Snapshot, new: &Snapshot) {
+        let mut changed_properties = HashMap::new();
+
+        // channels change when the active player gets a new owner
+        let active = |snapshot: &Snapshot| {
+            snapshot
+                .players
+                .first()
+                .map(|(name, channels, _)| (name.clone(), channels.clone()))
+        };
+        if active(old) != active(new) {
+            let active: Box<dyn RefArg + 'static> = Box::new(active_player(new));
+            changed_properties.insert(ACTIVE_PLAYER_PROPERTY.to_string(), Variant(active));
+        }
+
+        if old.players != new.players {
+            let players: Box<dyn RefArg + 'static> = Box::new(all_players(new));
+            changed_properties.insert(PLAYERS_PROPERTY.to_string(), Variant(players));
+        }
+
+        if old.pinned != new.pinned {
+            let pinned: Box<dyn RefArg + 'static> = Box::new(new.pinned);
+            changed_properties.insert(PINNED_PROPERTY.to_string(), Variant(pinned));
+        }
 
-        let active: Box<(dyn RefArg + 'static)> = Box::new(active.to_string());
         let props = PropertiesPropertiesChanged {
             interface_name: WELL_KNOWN_NAME.to_string(),
-            changed_properties: HashMap::from([(
-                ACTIVE_PLAYER_PROPERTY.to_string(),
-                Variant(active),
-            )]),
+            changed_properties,
             invalidated_properties: vec![],
         };
         let mut msg = Message::new_signal(WELL_KNOWN_PATH, PROPERTIES, PROPERTIES_CHANGED).unwrap();
         msg.append_all(props);
 
-        self.connection.send(msg).unwrap();
+        if self.connection.send(msg).is_err() {
+            eprintln!("cannot notify of changes");
+        }
     }
 }
 
-// TODO: new channels may be opened, would be nice to listen for it
+/// Save each state sent through `states` to `path`, off the event loop. Only the latest one of
+/// those sent while saving is written. Return once the sender is dropped and all are saved.
+async fn save_states(path: PathBuf, mut states: watch::Receiver<State>) {
+    while states.changed().await.is_ok() {
+        let state = states.borrow_and_update().clone();
+        let path = path.clone();
+        let saved = tokio::task::spawn_blocking(move || {
+            state.save(&path).map_err(|err| {
+                format!(
+                    "cannot save order of players to {}: {}",
+                    path.display(),
+                    err
+                )
+            })
+        })
+        .await;
+
+        match saved {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => eprintln!("{}", err),
+            Err(err) => eprintln!("cannot save order of players: {}", err),
+        }
+    }
+}
+
+/// Whether the player is playing, according to changed `props`. Signals without
+/// `PlaybackStatus`, e.g. of metadata changes, don't change it.
+fn read_status(props: &PropMap) -> Option<bool> {
+    prop_cast::<String>(props, "PlaybackStatus").map(|status| status == "Playing")
+}
+
+/// Value of `ActivePlayer`: name of the active player and its channels, empty if none.
+fn active_player(snapshot: &Snapshot) -> (String, Vec<String>) {
+    match snapshot.players.first() {
+        Some((active, channels, _)) => (
+            active.to_string(),
+            channels.iter().map(ToString::to_string).collect(),
+        ),
+        None => (String::new(), Vec::new()),
+    }
+}
+
+fn all_players(snapshot: &Snapshot) -> Vec<(String, Vec<String>, bool)> {
+    snapshot
+        .players
+        .iter()
+        .map(|(name, channels, playing)| {
+            let channels = channels.iter().map(ToString::to_string).collect();
+            (name.to_string(), channels, *playing)
+        })
+        .collect()
+}
+
+fn reply_with_player(player: Option<Arc<str>>) -> Result<(String,), MethodErr> {
+    match player {
+        Some(player) => Ok((player.to_string(),)),
+        None => Err(MethodErr::from((NO_PLAYERS_ERROR, "No players"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::props;
+
+    #[test]
+    fn reads_status_only_when_reported() {
+        let playing = props(vec![("PlaybackStatus", Box::new("Playing".to_owned()))]);
+        assert_eq!(read_status(&playing), Some(true));
+
+        let paused = props(vec![("PlaybackStatus", Box::new("Paused".to_owned()))]);
+        assert_eq!(read_status(&paused), Some(false));
+
+        let metadata = props(vec![("Metadata", Box::new(PropMap::new()))]);
+        assert_eq!(read_status(&metadata), None);
+    }
+}
@@ -1,114 +1,239 @@
+use crate::art::ArtCache;
+use crate::bus::{self, Backoff, Disconnected};
 use crate::constants::{
-    ACTIVE_PLAYER_PROPERTY, MPRIS_PATH, MPRIS_PLAYER_ITF, PROPERTIES, PROPERTIES_CHANGED,
-    WELL_KNOWN_PATH,
+    ACTIVE_PLAYER_PROPERTY, DBUS, MPRIS_PATH, MPRIS_PLAYER_ITF, PROPERTIES, PROPERTIES_CHANGED,
+    SEEKED, SERVICE_UNKNOWN_ERROR, WELL_KNOWN_NAME, WELL_KNOWN_PATH,
 };
+use crate::format::{Template, format_duration};
 use crate::get::get_active_player_impl;
 use dbus::arg;
-use dbus::arg::{RefArg, Variant, prop_cast};
+use dbus::arg::{ArgType, RefArg, Variant, prop_cast};
 use dbus::message::MatchRule;
 use dbus::nonblock::stdintf::org_freedesktop_dbus::{Properties, PropertiesPropertiesChanged};
 use dbus::nonblock::{MsgMatch, Proxy, SyncConnection};
-use dbus_tokio::connection;
-use std::fmt::{Display, Formatter};
+use serde_json::{Map, Value, json};
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::signal;
-use tokio::sync::Mutex;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{Mutex, Notify};
+
+/// Incremented on incompatible changes of the JSON printed by `follow`.
+const SCHEMA_VERSION: u32 = 1;
+
+/// Fields derived from position, which changes continuously while playing.
+const POSITION_FIELDS: [&str; 4] = ["position", "position_seconds", "position_text", "progress"];
 
 async fn change_metadata(
     changed_properties: arg::PropMap,
+    connection: Arc<SyncConnection>,
     data: Arc<Mutex<Data>>,
 ) -> Result<(), dbus::Error> {
-    let mut data = data.lock().await;
+    let player = {
+        let mut data = data.lock().await;
+        data.change_metadata(&changed_properties);
+
+        // players don't announce position changes, so it must be read again whenever it may jump
+        if !["Metadata", "PlaybackStatus", "Rate"]
+            .iter()
+            .any(|p| changed_properties.contains_key(*p))
+        {
+            data.print_changes();
+            return Ok(());
+        }
+        data.player_name.clone()
+    };
 
-    data.change_metadata(changed_properties);
-    println!("{}", data);
+    // not locked meanwhile, reading may take long if the player hangs
+    let position = read_position(&player, connection).await;
+
+    let mut data = data.lock().await;
+    if data.player_name == player {
+        data.change_position(position);
+    }
+    data.print_changes();
 
     Ok(())
 }
 
-// TODO: follow status
+async fn change_position(position: i64, data: Arc<Mutex<Data>>) {
+    let mut data = data.lock().await;
+
+    data.change_position(Some(position));
+    data.print(true);
+}
 
+/// Listen for changes of `player`, which became active with `switch`, instead of the previous one.
 async fn listen_for_metadata(
+    player: String,
+    switch: u64,
     connection: Arc<SyncConnection>,
     data: Arc<Mutex<Data>>,
 ) -> Result<(), dbus::Error> {
-    let player = data.lock().await.player_name.clone();
-
-    if player != "" {
+    let mut listens = Vec::new();
+    if !player.is_empty() {
         let data_clone = data.clone();
+        let connection_clone = connection.clone();
 
         let mr = MatchRule::new_signal(PROPERTIES, PROPERTIES_CHANGED)
             .with_path(MPRIS_PATH)
-            .with_sender(player);
+            .with_sender(player.clone());
         let m = connection
             .add_match(mr)
             .await?
             .cb(move |_, props: PropertiesPropertiesChanged| {
                 tokio::spawn(change_metadata(
                     props.changed_properties,
+                    connection_clone.clone(),
                     data_clone.clone(),
                 ));
 
                 true
             });
-        let mut data = data.lock().await;
-        if let Some(l) = &data.metadata_listen {
-            connection.remove_match(l.token()).await?;
-        }
-        data.metadata_listen = Some(m);
+        listens.push(m);
+
+        let data_clone = data.clone();
+        let mr = MatchRule::new_signal(MPRIS_PLAYER_ITF, SEEKED)
+            .with_path(MPRIS_PATH)
+            .with_sender(player);
+        let m = connection
+            .add_match(mr)
+            .await?
+            .cb(move |_, (position,): (i64,)| {
+                tokio::spawn(change_position(position, data_clone.clone()));
+                true
+            });
+        listens.push(m);
+    }
+
+    let mut data = data.lock().await;
+    let stale = if data.player_switch == switch {
+        std::mem::replace(&mut data.player_listens, listens)
     } else {
-        let mut data = data.lock().await;
-        if let Some(l) = &data.metadata_listen {
-            connection.remove_match(l.token()).await?;
-        }
-        data.metadata_listen = None;
+        // another player became active meanwhile, it listens by itself
+        listens
+    };
+    for l in stale {
+        connection.remove_match(l.token()).await?;
     }
 
     Ok(())
 }
 
+/// Convert D-Bus value to JSON, keeping its type. Dicts become objects, arrays and structs become
+/// arrays, variants are unwrapped.
+fn to_json_value(value: &dyn RefArg) -> Value {
+    match value.arg_type() {
+        ArgType::Boolean => Value::Bool(value.as_u64().is_some_and(|v| v != 0)),
+        ArgType::Byte | ArgType::UInt16 | ArgType::UInt32 | ArgType::UInt64 => {
+            value.as_u64().map_or(Value::Null, Value::from)
+        }
+        ArgType::Int16 | ArgType::Int32 | ArgType::Int64 => {
+            value.as_i64().map_or(Value::Null, Value::from)
+        }
+        ArgType::Double => value.as_f64().map_or(Value::Null, Value::from),
+        ArgType::String | ArgType::ObjectPath | ArgType::Signature => {
+            value.as_str().map_or(Value::Null, Value::from)
+        }
+        ArgType::Variant => value
+            .as_iter()
+            .and_then(|mut inner| inner.next().map(to_json_value))
+            .unwrap_or(Value::Null),
+        ArgType::Array if value.signature().starts_with("a{") => {
+            let mut object = Map::new();
+            if let Some(mut entries) = value.as_iter() {
+                while let (Some(key), Some(entry)) = (entries.next(), entries.next()) {
+                    let key = match key.as_str() {
+                        Some(key) => key.to_owned(),
+                        None => to_json_value(key).to_string(),
+                    };
+                    object.insert(key, to_json_value(entry));
+                }
+            }
+            Value::Object(object)
+        }
+        ArgType::Array | ArgType::Struct => value
+            .as_iter()
+            .map(|items| Value::Array(items.map(to_json_value).collect()))
+            .unwrap_or(Value::Null),
+        _ => Value::Null,
+    }
+}
+
+async fn read_position(player: &str, connection: Arc<SyncConnection>) -> Option<i64> {
+    let proxy = Proxy::new(player, MPRIS_PATH, Duration::from_secs(5), connection);
+
+    proxy.get::<i64>(MPRIS_PLAYER_ITF, "Position").await.ok()
+}
+
+async fn read_player(
+    player: &str,
+    connection: Arc<SyncConnection>,
+) -> Result<arg::PropMap, dbus::Error> {
+    let proxy = Proxy::new(player, MPRIS_PATH, Duration::from_secs(5), connection);
+
+    proxy.get_all(MPRIS_PLAYER_ITF).await
+}
+
+/// State of `player` in the same form as printed by `follow`.
+pub async fn player_state(
+    player: String,
+    connection: Arc<SyncConnection>,
+) -> Result<Value, dbus::Error> {
+    let mut data = Data {
+        daemon_running: true,
+        ..Default::default()
+    };
+    data.change_player(player.clone());
+
+    if !player.is_empty() {
+        let props = read_player(&player, connection).await?;
+        data.change_all(&props);
+    }
+
+    Ok(data.to_json())
+}
+
+/// Switch to `new_player_name`. Switches overlap, e.g. while the restarted daemon adds players
+/// again, so results of a switch are dropped once another one started.
 async fn change_player(
     new_player_name: String,
     connection: Arc<SyncConnection>,
     data: Arc<Mutex<Data>>,
 ) -> Result<(), dbus::Error> {
-    {
+    let switch = {
         let mut data = data.lock().await;
         data.change_player(new_player_name.clone());
 
         if new_player_name.is_empty() {
-            println!("{}", data);
-            return Ok(());
+            data.print_changes();
         }
-    }
+        data.player_switch
+    };
 
-    let proxy = Proxy::new(
-        new_player_name,
-        MPRIS_PATH,
-        Duration::from_secs(5),
+    // listen before reading, so changes meanwhile aren't missed, and even if reading fails
+    listen_for_metadata(
+        new_player_name.clone(),
+        switch,
         connection.clone(),
-    );
+        data.clone(),
+    )
+    .await?;
 
-    let props = proxy
-        .get::<arg::PropMap>(MPRIS_PLAYER_ITF, "Metadata")
-        .await?;
-    {
+    if !new_player_name.is_empty() {
+        let props = read_player(&new_player_name, connection).await;
         let mut data = data.lock().await;
-        data.change_metadata(props);
-    }
-
-    let props = proxy
-        .get::<String>(MPRIS_PLAYER_ITF, "PlaybackStatus")
-        .await?;
-    {
-        let mut data = data.lock().await;
-        data.change_status(&props);
-        println!("{}", data);
+        if data.player_switch != switch {
+            return Ok(());
+        }
+        match props {
+            Ok(props) => data.change_all(&props),
+            Err(err) => eprintln!("cannot read state of {:?}: {}", new_player_name, err),
+        }
+        data.print_changes();
     }
 
-    listen_for_metadata(connection.clone(), data.clone()).await?;
-
     Ok(())
 }
 
@@ -116,14 +241,14 @@ async fn listen_for_player_changes(
     connection: Arc<SyncConnection>,
     data: Arc<Mutex<Data>>,
 ) -> Result<MsgMatch, dbus::Error> {
-    let mr = MatchRule::new_signal(PROPERTIES, PROPERTIES_CHANGED).with_path(WELL_KNOWN_PATH);
+    let mr = MatchRule::new_signal(PROPERTIES, PROPERTIES_CHANGED)
+        .with_path(WELL_KNOWN_PATH)
+        .with_sender(WELL_KNOWN_NAME);
     let m = connection
         .add_match(mr)
         .await?
         .cb(move |_, props: PropertiesPropertiesChanged| {
-            if let Some(name) =
-                prop_cast::<String>(&props.changed_properties, ACTIVE_PLAYER_PROPERTY)
-            {
+            if let Some(name) = read_active_player(&props.changed_properties) {
                 tokio::spawn(change_player(
                     name.clone(),
                     connection.clone(),
@@ -136,176 +261,805 @@ async fn listen_for_player_changes(
     Ok(m)
 }
 
+/// Name of the active player from changed `props`, `ActivePlayer` is sent along with its channels.
+fn read_active_player(props: &arg::PropMap) -> Option<String> {
+    let mut fields = props.get(ACTIVE_PLAYER_PROPERTY)?.0.as_iter()?;
+    fields.next()?.as_str().map(ToOwned::to_owned)
+}
+
+/// Follow the daemon starting or stopping, e.g. when restarted.
+async fn listen_for_daemon(
+    connection: Arc<SyncConnection>,
+    data: Arc<Mutex<Data>>,
+) -> Result<MsgMatch, dbus::Error> {
+    let mr = MatchRule::new_signal(DBUS, "NameOwnerChanged").with_sender(DBUS);
+    let m = connection.add_match(mr).await?.cb(
+        move |_, (name, _, new_owner): (String, String, String)| {
+            if name == WELL_KNOWN_NAME {
+                tokio::spawn(change_daemon(
+                    !new_owner.is_empty(),
+                    connection.clone(),
+                    data.clone(),
+                ));
+            }
+            true
+        },
+    );
+
+    Ok(m)
+}
+
+async fn change_daemon(
+    running: bool,
+    connection: Arc<SyncConnection>,
+    data: Arc<Mutex<Data>>,
+) -> Result<(), dbus::Error> {
+    let (running, player) = if running {
+        match get_active_player_impl(connection.clone()).await {
+            Ok((player, _)) => (true, player),
+            Err(err) => {
+                // the daemon is not running, or stopped meanwhile
+                if err.name() != Some(SERVICE_UNKNOWN_ERROR) {
+                    eprintln!("cannot get active player: {}", err);
+                }
+                (false, String::new())
+            }
+        }
+    } else {
+        (false, String::new())
+    };
+
+    data.lock().await.daemon_running = running;
+    change_player(player, connection, data).await
+}
+
 #[derive(Default)]
 struct Data {
+    daemon_running: bool,
     player_name: String,
-    metadata_listen: Option<MsgMatch>,
+    /// incremented whenever the player changes
+    player_switch: u64,
+    player_listens: Vec<MsgMatch>,
+    format: Option<Template>,
+    /// whether to print all metadata reported by the player
+    include_metadata: bool,
+    /// notified of changes to print, when they are debounced
+    pending_changes: Option<Arc<Notify>>,
+    last_output: Option<String>,
+    /// receives `art_url`s to cache, when caching is enabled
+    art_requests: Option<UnboundedSender<String>>,
+    art_fallback: Option<PathBuf>,
+    last_state: Option<Value>,
 
     playing: bool,
 
-    length: Option<i32>,
+    can_play: bool,
+    can_pause: bool,
+    can_go_next: bool,
+    can_go_previous: bool,
+    can_seek: bool,
+    can_control: bool,
+
+    /// last known position and when it was read, extrapolated while playing
+    position: Option<(i64, Instant)>,
+    rate: Option<f64>,
+    minimum_rate: Option<f64>,
+    maximum_rate: Option<f64>,
+
+    volume: Option<f64>,
+    shuffle: Option<bool>,
+    loop_status: Option<String>,
+
+    /// microseconds
+    length: Option<i64>,
     album: Option<String>,
     album_artist: Option<String>,
     art_url: Option<String>,
+    /// `art_url` for which `art_path` was requested
+    art_requested: Option<String>,
+    art_path: Option<PathBuf>,
     title: Option<String>,
     track_number: Option<i32>,
     disc_number: Option<i32>,
     url: Option<String>,
     artist: Option<String>,
+    metadata: Map<String, Value>,
 }
 
 impl Data {
     fn change_player(&mut self, name: String) {
         self.player_name = name;
+        self.player_switch += 1;
         self.playing = false;
+        self.can_play = false;
+        self.can_pause = false;
+        self.can_go_next = false;
+        self.can_go_previous = false;
+        self.can_seek = false;
+        self.can_control = false;
+        self.position = None;
+        self.rate = None;
+        self.minimum_rate = None;
+        self.maximum_rate = None;
+        self.volume = None;
+        self.shuffle = None;
+        self.loop_status = None;
         self.length = None;
         self.album = None;
         self.album_artist = None;
         self.art_url = None;
+        self.request_art();
         self.title = None;
         self.track_number = None;
         self.disc_number = None;
         self.url = None;
         self.artist = None;
+        self.metadata.clear();
     }
 
-    fn vec_or_str(v: &Variant<Box<dyn RefArg>>) -> String {
+    /// String, or array of strings (possibly nested) joined with commas.
+    fn vec_or_str(v: &dyn RefArg) -> Option<String> {
         if let Some(v) = v.as_str() {
-            v.to_owned()
-        } else {
-            let v = v
-                .as_iter()
-                .unwrap()
-                .flat_map(|s| s.as_iter().unwrap().map(|s| s.as_str().unwrap().to_owned()))
-                .collect::<Vec<_>>();
-            v.join(", ")
+            return Some(v.to_owned());
+        }
+        if v.signature().starts_with("a{") {
+            return None;
         }
+
+        let v = v
+            .as_iter()?
+            .map(Self::vec_or_str)
+            .collect::<Option<Vec<_>>>()?;
+        Some(v.join(", "))
     }
 
-    fn read_len(v: &Variant<Box<dyn RefArg>>) -> i32 {
-        if let Some(v) = v.as_u64() {
-            v as i32
-        } else if let Some(v) = v.as_i64() {
-            v as i32
+    /// Any integer or double, or a string containing a number.
+    fn read_number(v: &dyn RefArg) -> Option<i64> {
+        if let Some(v) = v.as_i64() {
+            Some(v)
+        } else if let Some(v) = v.as_u64() {
+            i64::try_from(v).ok()
+        } else if let Some(v) = v.as_f64() {
+            v.is_finite().then_some(v as i64)
+        } else if let Some(v) = v.as_str() {
+            let v = v.trim();
+            v.parse().ok().or_else(|| {
+                v.parse::<f64>()
+                    .ok()
+                    .filter(|v| v.is_finite())
+                    .map(|v| v as i64)
+            })
         } else {
-            println!("{:?} = {:?}", v, v.arg_type());
-            panic!("unknown type");
+            None
+        }
+    }
+
+    fn read_len(v: &dyn RefArg) -> Option<i64> {
+        Self::read_number(v).filter(|v| *v >= 0)
+    }
+
+    fn read_index(v: &dyn RefArg) -> Option<i32> {
+        Self::read_number(v).and_then(|v| i32::try_from(v).ok())
+    }
+
+    fn read_string(v: &dyn RefArg) -> Option<String> {
+        Self::vec_or_str(v)
+    }
+
+    /// Decode `value` of metadata field `prop`, logging values which can't be decoded.
+    fn decode<T>(
+        prop: &str,
+        value: &Variant<Box<dyn RefArg>>,
+        read: fn(&dyn RefArg) -> Option<T>,
+    ) -> Option<T> {
+        let decoded = read(value);
+        if decoded.is_none() {
+            eprintln!(
+                "ignoring {}: unexpected value {:?} of type {}",
+                prop,
+                value,
+                value.0.signature()
+            );
+        }
+        decoded
+    }
+
+    fn change_capabilities(&mut self, props: &arg::PropMap) {
+        for (prop, flag) in [
+            ("CanPlay", &mut self.can_play),
+            ("CanPause", &mut self.can_pause),
+            ("CanGoNext", &mut self.can_go_next),
+            ("CanGoPrevious", &mut self.can_go_previous),
+            ("CanSeek", &mut self.can_seek),
+            ("CanControl", &mut self.can_control),
+        ] {
+            if let Some(value) = arg::prop_cast::<bool>(props, prop) {
+                *flag = *value;
+            }
         }
     }
 
-    fn escape(s: &str) -> String {
-        s.replace("\"", "\\\"")
+    fn change_settings(&mut self, props: &arg::PropMap) {
+        if let Some(rate) = prop_cast::<f64>(props, "Rate") {
+            self.change_rate(*rate);
+        }
+        if let Some(rate) = prop_cast::<f64>(props, "MinimumRate") {
+            self.minimum_rate = Some(*rate);
+        }
+        if let Some(rate) = prop_cast::<f64>(props, "MaximumRate") {
+            self.maximum_rate = Some(*rate);
+        }
+        if let Some(volume) = prop_cast::<f64>(props, "Volume") {
+            self.volume = Some(*volume);
+        }
+        if let Some(shuffle) = prop_cast::<bool>(props, "Shuffle") {
+            self.shuffle = Some(*shuffle);
+        }
+        if let Some(status) = prop_cast::<String>(props, "LoopStatus") {
+            self.loop_status = Some(status.clone());
+        }
     }
 
-    fn read_string(v: &Variant<Box<dyn RefArg>>) -> String {
-        Self::escape(&Self::vec_or_str(v))
+    fn change_all(&mut self, props: &arg::PropMap) {
+        self.change_capabilities(props);
+        self.change_settings(props);
+        if let Some(metadata) = prop_cast::<arg::PropMap>(props, "Metadata") {
+            self.change_metadata(metadata);
+        }
+        if let Some(status) = prop_cast::<String>(props, "PlaybackStatus") {
+            self.change_status(status);
+        }
+        if let Some(position) = prop_cast::<i64>(props, "Position") {
+            self.change_position(Some(*position));
+        }
     }
 
-    fn change_metadata(&mut self, props: arg::PropMap) {
-        if let Some(playback) = arg::prop_cast::<String>(&props, "PlaybackStatus") {
+    fn change_metadata(&mut self, props: &arg::PropMap) {
+        self.change_capabilities(props);
+        self.change_settings(props);
+
+        if let Some(playback) = arg::prop_cast::<String>(props, "PlaybackStatus") {
             self.change_status(playback);
-            return;
         }
 
-        let props = if let Some(metadata) = arg::prop_cast::<arg::PropMap>(&props, "Metadata") {
+        let props = if let Some(metadata) = arg::prop_cast::<arg::PropMap>(props, "Metadata") {
+            self.clear_metadata();
             metadata
         } else {
-            &props
+            props
         };
 
         for (prop, value) in props {
+            // metadata keys are namespaced, unlike player properties which may get here
+            if prop.contains(':') {
+                self.metadata.insert(prop.clone(), to_json_value(value));
+            }
             match prop.as_str() {
-                "mpris:length" => self.length = Some(Self::read_len(value)),
-                "xesam:album" => self.album = Some(Self::read_string(value)),
-                "xesam:albumArtist" => self.album_artist = Some(Self::read_string(value)),
-                "mpris:artUrl" => self.art_url = Some(Self::read_string(value)),
-                "xesam:title" => self.title = Some(Self::read_string(value)),
-                "xesam:trackNumber" => self.track_number = Some(value.as_i64().unwrap() as i32),
-                "xesam:discNumber" => self.disc_number = Some(value.as_i64().unwrap() as i32),
-                "xesam:url" => self.url = Some(Self::read_string(value)),
-                "xesam:artist" => self.artist = Some(Self::read_string(value)),
+                "mpris:length" => self.length = Self::decode(prop, value, Self::read_len),
+                "xesam:album" => self.album = Self::decode(prop, value, Self::read_string),
+                "xesam:albumArtist" => {
+                    self.album_artist = Self::decode(prop, value, Self::read_string)
+                }
+                "mpris:artUrl" => self.art_url = Self::decode(prop, value, Self::read_string),
+                "xesam:title" => self.title = Self::decode(prop, value, Self::read_string),
+                "xesam:trackNumber" => {
+                    self.track_number = Self::decode(prop, value, Self::read_index)
+                }
+                "xesam:discNumber" => {
+                    self.disc_number = Self::decode(prop, value, Self::read_index)
+                }
+                "xesam:url" => self.url = Self::decode(prop, value, Self::read_string),
+                "xesam:artist" => self.artist = Self::decode(prop, value, Self::read_string),
                 _ => (),
             }
         }
+        // players send a hash map, keep the output stable
+        self.metadata.sort_keys();
+
+        self.request_art();
+    }
+
+    /// Forget metadata of the previous track, which is replaced as a whole, so fields the new
+    /// one lacks aren't left over.
+    fn clear_metadata(&mut self) {
+        self.metadata.clear();
+        self.length = None;
+        self.album = None;
+        self.album_artist = None;
+        self.art_url = None;
+        self.title = None;
+        self.track_number = None;
+        self.disc_number = None;
+        self.url = None;
+        self.artist = None;
+    }
+
+    /// Ask for local copy of `art_url`, if it changed.
+    fn request_art(&mut self) {
+        let Some(art_requests) = &self.art_requests else {
+            return;
+        };
+        if self.art_requested == self.art_url {
+            return;
+        }
+
+        self.art_path = None;
+        self.art_requested = self.art_url.clone();
+        if let Some(url) = &self.art_url {
+            // the receiver lives as long as follow
+            let _ = art_requests.send(url.clone());
+        }
     }
 
     fn change_status(&mut self, status: &str) {
+        self.change_position(self.current_position());
         self.playing = status == "Playing";
     }
+
+    fn change_rate(&mut self, rate: f64) {
+        self.change_position(self.current_position());
+        self.rate = Some(rate);
+    }
+
+    fn change_position(&mut self, position: Option<i64>) {
+        self.position = position.map(|position| (position, Instant::now()));
+    }
+
+    /// Position extrapolated from the last known one, as players don't report its changes.
+    fn current_position(&self) -> Option<i64> {
+        let (position, read_at) = self.position?;
+        if !self.playing {
+            return Some(position);
+        }
+
+        let elapsed = read_at.elapsed().as_micros() as f64 * self.rate.unwrap_or(1.0);
+        let position = (position + elapsed as i64).max(0);
+        Some(match self.length {
+            Some(length) if length > 0 => position.min(length),
+            _ => position,
+        })
+    }
+
+    fn progress(&self) -> Option<f64> {
+        match (self.current_position(), self.length) {
+            (Some(position), Some(length)) if length > 0 => Some(position as f64 / length as f64),
+            _ => None,
+        }
+    }
+}
+
+fn to_seconds(microseconds: i64) -> f64 {
+    microseconds as f64 / 1_000_000.0
 }
 
-impl Display for Data {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let length = (&self.length).map(|s| s.to_string()).unwrap_or_default();
-        let album = (&self.album).as_ref().map(|s| s.as_str()).unwrap_or("");
-        let album_artist = (&self.album_artist)
+impl Data {
+    fn to_json(&self) -> Value {
+        let player = Some(&self.player_name).filter(|name| !name.is_empty());
+        let position = self.current_position();
+
+        let mut json = json!({
+            "version": SCHEMA_VERSION,
+            "daemon": self.daemon_running,
+            "player": player,
+            "playing": self.playing,
+            "can_play": self.can_play,
+            "can_pause": self.can_pause,
+            "can_go_next": self.can_go_next,
+            "can_go_previous": self.can_go_previous,
+            "can_seek": self.can_seek,
+            "can_control": self.can_control,
+            "volume": self.volume,
+            "shuffle": self.shuffle,
+            "loop_status": self.loop_status,
+            "rate": self.rate,
+            "minimum_rate": self.minimum_rate,
+            "maximum_rate": self.maximum_rate,
+            "position": position,
+            "position_seconds": position.map(to_seconds),
+            "position_text": position.map(format_duration),
+            "progress": self.progress(),
+            "length": self.length,
+            "length_seconds": self.length.map(to_seconds),
+            "length_text": self.length.map(format_duration),
+            "album": self.album,
+            "album_artist": self.album_artist,
+            "art_url": self.art_url,
+            "art_path": self
+                .art_path
+                .as_ref()
+                .or(self.art_fallback.as_ref())
+                .map(|path| path.to_string_lossy()),
+            "title": self.title,
+            "track_number": self.track_number,
+            "disc_number": self.disc_number,
+            "url": self.url,
+            "artist": self.artist,
+        });
+
+        if self.include_metadata {
+            json["metadata"] = Value::Object(self.metadata.clone());
+        }
+
+        json
+    }
+}
+
+impl Data {
+    /// Print the state, unless the line is the same as the last one. Extrapolated position
+    /// differs on every call, so unless `position_changed` it doesn't count as a change.
+    fn print(&mut self, position_changed: bool) {
+        let mut state = self.to_json();
+        let output = match &self.format {
+            Some(format) => format.render(&state),
+            None => state.to_string(),
+        };
+        if let Some(state) = state.as_object_mut() {
+            for field in POSITION_FIELDS {
+                state.remove(field);
+            }
+        }
+
+        let changed = position_changed || self.last_state.as_ref() != Some(&state);
+        if changed && self.last_output.as_ref() != Some(&output) {
+            println!("{}", output);
+            self.last_output = Some(output);
+            self.last_state = Some(state);
+        }
+    }
+
+    fn print_changes(&mut self) {
+        match &self.pending_changes {
+            Some(pending_changes) => pending_changes.notify_one(),
+            None => self.print(false),
+        }
+    }
+}
+
+/// Print the state every `period` while the player is playing.
+async fn tick(period: Duration, data: Arc<Mutex<Data>>) {
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    // the first tick completes immediately, right after the initial state was printed
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        let mut data = data.lock().await;
+        if data.playing && !data.player_name.is_empty() {
+            data.print(true);
+        }
+    }
+}
+
+/// Print the state once no change came for `period`, so a burst of changes is printed once.
+async fn debounce(period: Duration, pending_changes: Arc<Notify>, data: Arc<Mutex<Data>>) {
+    loop {
+        pending_changes.notified().await;
+
+        let mut deadline = tokio::time::Instant::now() + period;
+        loop {
+            tokio::select! {
+                _ = pending_changes.notified() => deadline = tokio::time::Instant::now() + period,
+                _ = tokio::time::sleep_until(deadline) => break,
+            }
+        }
+
+        data.lock().await.print(false);
+    }
+}
+
+/// Cache requested art, skipping requests which became outdated meanwhile.
+async fn cache_art(
+    mut cache: ArtCache,
+    mut art_requests: UnboundedReceiver<String>,
+    data: Arc<Mutex<Data>>,
+) {
+    while let Some(mut url) = art_requests.recv().await {
+        while let Ok(newer) = art_requests.try_recv() {
+            url = newer;
+        }
+
+        let (returned, path) = tokio::task::spawn_blocking(move || {
+            let path = cache.resolve(&url);
+            (
+                cache,
+                path.map_err(|e| format!("cannot cache art {}: {}", url, e)),
+            )
+        })
+        .await
+        .expect("art caching panicked");
+        cache = returned;
+
+        let path = path.inspect_err(|e| eprintln!("{}", e)).ok();
+        let mut data = data.lock().await;
+        if data.art_requested == data.art_url && path != data.art_path {
+            data.art_path = path;
+            data.print_changes();
+        }
+    }
+}
+
+pub async fn follow_changes(
+    format: Option<Template>,
+    tick_period: Option<Duration>,
+    include_metadata: bool,
+    debounce_period: Option<Duration>,
+    art_cache: Option<ArtCache>,
+) -> Result<(), dbus::Error> {
+    let pending_changes = debounce_period.map(|_| Arc::new(Notify::new()));
+    let art_requests = art_cache.as_ref().map(|_| mpsc::unbounded_channel());
+    let data = Arc::new(Mutex::new(Data {
+        format,
+        include_metadata,
+        pending_changes: pending_changes.clone(),
+        art_requests: art_requests.as_ref().map(|(sender, _)| sender.clone()),
+        art_fallback: art_cache
             .as_ref()
-            .map(|s| s.as_str())
-            .unwrap_or("");
-        let art_url = (&self.art_url).as_ref().map(|s| s.as_str()).unwrap_or("");
-        let title = (&self.title).as_ref().map(|s| s.as_str()).unwrap_or("");
-        let track_number = (&self.track_number)
-            .map(|s| s.to_string())
-            .unwrap_or_default();
-        let disc_number = (&self.disc_number)
-            .map(|s| s.to_string())
-            .unwrap_or_default();
-        let url = (&self.url).as_ref().map(|s| s.as_str()).unwrap_or("");
-        let artist = (&self.artist).as_ref().map(|s| s.as_str()).unwrap_or("");
-
-        write!(
-            f,
-            "\
-        {{ \
-        \"player\": \"{}\", \
-        \"playing\": \"{}\", \
-        \"length\": \"{}\", \
-        \"album\": \"{}\", \
-        \"album_artist\": \"{}\", \
-        \"art_url\": \"{}\", \
-        \"title\": \"{}\", \
-        \"track_number\": \"{}\", \
-        \"disc_number\": \"{}\", \
-        \"url\": \"{}\", \
-        \"artist\": \"{}\" \
-         }}\
-        ",
-            self.player_name,
-            self.playing,
-            length,
-            album,
-            album_artist,
-            art_url,
-            title,
-            track_number,
-            disc_number,
-            url,
-            artist,
-        )
+            .and_then(|cache| cache.fallback())
+            .map(ToOwned::to_owned),
+        ..Default::default()
+    }));
+    if let (Some(period), Some(pending_changes)) = (debounce_period, pending_changes) {
+        tokio::spawn(debounce(period, pending_changes, data.clone()));
+    }
+    if let (Some(cache), Some((_, receiver))) = (art_cache, art_requests) {
+        tokio::spawn(cache_art(cache, receiver, data.clone()));
+    }
+    if let Some(period) = tick_period {
+        tokio::spawn(tick(period, data.clone()));
+    }
+
+    let mut backoff = Backoff::default();
+    let mut connected_once = false;
+    loop {
+        let (connection, disconnected) = match bus::connect() {
+            Ok(connection) => connection,
+            Err(err) if connected_once => {
+                eprintln!("cannot connect to D-Bus: {}", err);
+                tokio::select! {
+                    _ = backoff.wait() => continue,
+                    _ = signal::ctrl_c() => return Ok(()),
+                }
+            }
+            Err(err) => return Err(err),
+        };
+        connected_once = true;
+        backoff.reset();
+
+        match follow_connection(connection.clone(), disconnected, data.clone()).await {
+            Ok(true) => return Ok(()),
+            Ok(false) => {}
+            Err(err) if bus::is_connected(&connection) => return Err(err),
+            Err(err) => eprintln!("{}", err),
+        }
+        eprintln!("reconnecting to D-Bus");
+
+        {
+            let mut data = data.lock().await;
+            data.daemon_running = false;
+            data.change_player(String::new());
+            data.print_changes();
+        }
+
+        tokio::select! {
+            _ = backoff.wait() => {}
+            _ = signal::ctrl_c() => return Ok(()),
+        }
+    }
+}
+
+/// Follow changes on the current connection. Return whether interrupted by user, `false` means
+/// the connection was lost.
+async fn follow_connection(
+    connection: Arc<SyncConnection>,
+    disconnected: Disconnected,
+    data: Arc<Mutex<Data>>,
+) -> Result<bool, dbus::Error> {
+    let mut listens = Vec::new();
+    let result = follow_until_stopped(&connection, &mut listens, disconnected, data.clone()).await;
+
+    // listeners keep the connection alive
+    listens.append(&mut data.lock().await.player_listens);
+    bus::stop_listening(&connection, listens).await;
+
+    result
+}
+
+/// Added matches are pushed to `listens`, to be removed however this ends.
+async fn follow_until_stopped(
+    connection: &Arc<SyncConnection>,
+    listens: &mut Vec<MsgMatch>,
+    mut disconnected: Disconnected,
+    data: Arc<Mutex<Data>>,
+) -> Result<bool, dbus::Error> {
+    listens.push(listen_for_daemon(connection.clone(), data.clone()).await?);
+    listens.push(listen_for_player_changes(connection.clone(), data.clone()).await?);
+    change_daemon(true, connection.clone(), data.clone()).await?;
+
+    tokio::select! {
+        result = signal::ctrl_c() => result.expect("failed to listen for event"),
+        reason = &mut disconnected => {
+            eprintln!("lost connection to D-Bus: {}", reason.unwrap_or_default());
+            return Ok(false);
+        }
     }
+
+    Ok(true)
 }
 
-pub async fn follow_changes() -> Result<(), dbus::Error> {
-    let (resource, connection) = connection::new_session_sync()?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::props;
+    use dbus::arg::PropMap;
 
-    tokio::spawn(async {
-        let err = resource.await;
-        panic!("Lost connection to D-Bus: {}", err);
-    });
+    fn changed_metadata(metadata: PropMap) -> PropMap {
+        props(vec![("Metadata", Box::new(metadata))])
+    }
 
-    let data = Arc::new(Mutex::new(Data::default()));
-    let (player, _) = get_active_player_impl(connection.clone()).await?;
-    change_player(player, connection.clone(), data.clone()).await?;
+    #[test]
+    fn reads_active_player_with_channels() {
+        let changed = props(vec![(
+            ACTIVE_PLAYER_PROPERTY,
+            Box::new((
+                "org.mpris.MediaPlayer2.vlc".to_owned(),
+                vec![":1.1".to_owned()],
+            )),
+        )]);
+        assert_eq!(
+            read_active_player(&changed).as_deref(),
+            Some("org.mpris.MediaPlayer2.vlc")
+        );
+        assert_eq!(read_active_player(&props(Vec::new())), None);
+    }
 
-    let player_listen = listen_for_player_changes(connection.clone(), data.clone()).await?;
+    #[test]
+    fn decodes_usual_encodings() {
+        let mut data = Data::default();
+        data.change_metadata(&changed_metadata(props(vec![
+            ("mpris:length", Box::new(300_000_000i64)),
+            ("xesam:title", Box::new("Title".to_owned())),
+            (
+                "xesam:artist",
+                Box::new(vec!["A".to_owned(), "B".to_owned()]),
+            ),
+            ("xesam:trackNumber", Box::new(3i32)),
+            ("xesam:discNumber", Box::new(1u32)),
+        ])));
 
-    signal::ctrl_c().await.expect("failed to listen for event");
+        assert_eq!(data.length, Some(300_000_000));
+        assert_eq!(data.title.as_deref(), Some("Title"));
+        assert_eq!(data.artist.as_deref(), Some("A, B"));
+        assert_eq!(data.track_number, Some(3));
+        assert_eq!(data.disc_number, Some(1));
+    }
 
-    connection.remove_match(player_listen.token()).await?;
+    #[test]
+    fn decodes_unusual_encodings() {
+        let mut data = Data::default();
+        data.change_metadata(&changed_metadata(props(vec![
+            ("mpris:length", Box::new(300_000_000.0f64)),
+            ("xesam:trackNumber", Box::new("7".to_owned())),
+            ("xesam:discNumber", Box::new(2u64)),
+            (
+                "xesam:albumArtist",
+                Box::new(Variant(Box::new(vec!["C".to_owned()]) as Box<dyn RefArg>)),
+            ),
+        ])));
 
-    if let Some(l) = &data.lock().await.metadata_listen {
-        connection.remove_match(l.token()).await?;
+        assert_eq!(data.length, Some(300_000_000));
+        assert_eq!(data.track_number, Some(7));
+        assert_eq!(data.disc_number, Some(2));
+        assert_eq!(data.album_artist.as_deref(), Some("C"));
     }
 
-    Ok(())
+    #[test]
+    fn forgets_metadata_of_previous_track() {
+        let mut data = Data::default();
+        data.change_metadata(&changed_metadata(props(vec![
+            ("xesam:title", Box::new("First".to_owned())),
+            ("xesam:album", Box::new("Album".to_owned())),
+            ("mpris:length", Box::new(300_000_000i64)),
+            ("mpris:artUrl", Box::new("file:///cover.png".to_owned())),
+        ])));
+        assert_eq!(data.album.as_deref(), Some("Album"));
+
+        data.change_metadata(&changed_metadata(props(vec![(
+            "xesam:title",
+            Box::new("Second".to_owned()),
+        )])));
+
+        assert_eq!(data.title.as_deref(), Some("Second"));
+        assert_eq!(data.album, None);
+        assert_eq!(data.length, None);
+        assert_eq!(data.art_url, None);
+        assert_eq!(data.to_json()["album"], Value::Null);
+    }
+
+    #[test]
+    fn changes_status_and_metadata_together() {
+        let mut data = Data::default();
+        let mut changed =
+            changed_metadata(props(vec![("xesam:title", Box::new("Title".to_owned()))]));
+        changed.extend(props(vec![
+            ("PlaybackStatus", Box::new("Playing".to_owned())),
+            ("Volume", Box::new(0.25f64)),
+            ("Shuffle", Box::new(true)),
+            ("LoopStatus", Box::new("Track".to_owned())),
+            ("Rate", Box::new(1.5f64)),
+        ]));
+        data.change_metadata(&changed);
+
+        assert!(data.playing);
+        assert_eq!(data.title.as_deref(), Some("Title"));
+        assert_eq!(data.volume, Some(0.25));
+        assert_eq!(data.shuffle, Some(true));
+        assert_eq!(data.loop_status.as_deref(), Some("Track"));
+        assert_eq!(data.rate, Some(1.5));
+    }
+
+    #[test]
+    fn keeps_long_lengths() {
+        let mut data = Data::default();
+        data.change_metadata(&changed_metadata(props(vec![(
+            "mpris:length",
+            Box::new(3 * 3600 * 1_000_000u64),
+        )])));
+
+        let json = data.to_json();
+        assert_eq!(json["length"], json!(10_800_000_000i64));
+        assert_eq!(json["length_seconds"], json!(10_800.0));
+        assert_eq!(json["length_text"], json!("3:00:00"));
+    }
+
+    #[test]
+    fn skips_undecodable_values() {
+        let mut data = Data::default();
+        data.change_metadata(&changed_metadata(props(vec![
+            ("mpris:length", Box::new("long".to_owned())),
+            ("xesam:title", Box::new(true)),
+            ("xesam:artist", Box::new(vec![1i32, 2])),
+            ("xesam:trackNumber", Box::new(u64::MAX)),
+            ("xesam:discNumber", Box::new(f64::NAN)),
+            ("xesam:album", Box::new(props(vec![("a", Box::new(1i32))]))),
+            ("xesam:url", Box::new(("a".to_owned(), 1i32))),
+            ("xesam:comment", Box::new(vec![vec![0u8]])),
+        ])));
+
+        assert_eq!(data.length, None);
+        assert_eq!(data.title, None);
+        assert_eq!(data.artist, None);
+        assert_eq!(data.track_number, None);
+        assert_eq!(data.disc_number, None);
+        assert_eq!(data.album, None);
+        assert_eq!(data.url, None);
+        assert_eq!(
+            data.metadata.get("xesam:comment"),
+            Some(&Value::from(vec![vec![0]]))
+        );
+    }
+
+    #[test]
+    fn keeps_types_in_metadata() {
+        let mut data = Data::default();
+        data.change_metadata(&changed_metadata(props(vec![
+            ("xesam:userRating", Box::new(0.5f64)),
+            ("xesam:useCount", Box::new(3i32)),
+            ("xesam:genre", Box::new(vec!["Rock".to_owned()])),
+            ("spotify:explicit", Box::new(false)),
+            ("mpris:trackid", Box::new(dbus::Path::from("/track/1"))),
+        ])));
+
+        assert_eq!(
+            Value::Object(data.metadata),
+            json!({
+                "mpris:trackid": "/track/1",
+                "spotify:explicit": false,
+                "xesam:genre": ["Rock"],
+                "xesam:useCount": 3,
+                "xesam:userRating": 0.5,
+            })
+        );
+    }
 }
@@ -0,0 +1,254 @@
+use serde_json::Value;
+
+/// Format microseconds as `M:SS` or `H:MM:SS`.
+pub fn format_duration(microseconds: i64) -> String {
+    let sign = if microseconds < 0 { "-" } else { "" };
+    let seconds = microseconds.unsigned_abs() / 1_000_000;
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+
+    if hours > 0 {
+        format!("{}{}:{:02}:{:02}", sign, hours, minutes, seconds)
+    } else {
+        format!("{}{}:{:02}", sign, minutes, seconds)
+    }
+}
+
+fn escape_markup(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn to_text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        Value::Array(values) => values.iter().map(to_text).collect::<Vec<_>>().join(", "),
+        value => value.to_string(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Helper {
+    Duration,
+    Truncate(usize),
+    Default(String),
+    Upper,
+    Lower,
+    Markup,
+}
+
+impl Helper {
+    fn parse(s: &str) -> Result<Self, String> {
+        let (name, arg) = match s.split_once(':') {
+            Some((name, arg)) => (name.trim(), Some(arg)),
+            None => (s.trim(), None),
+        };
+
+        match (name, arg) {
+            ("duration", None) => Ok(Helper::Duration),
+            ("truncate", Some(n)) => n
+                .trim()
+                .parse()
+                .map(Helper::Truncate)
+                .map_err(|_| format!("invalid length in {:?}", s)),
+            ("default", Some(text)) => Ok(Helper::Default(text.to_owned())),
+            ("upper", None) => Ok(Helper::Upper),
+            ("lower", None) => Ok(Helper::Lower),
+            ("markup", None) => Ok(Helper::Markup),
+            _ => Err(format!(
+                "unknown helper {:?}, expected duration, truncate:N, default:TEXT, upper, lower or markup",
+                s
+            )),
+        }
+    }
+
+    fn apply(&self, value: Value) -> Value {
+        match self {
+            Helper::Duration => match value.as_i64() {
+                Some(microseconds) => Value::String(format_duration(microseconds)),
+                None => value,
+            },
+            Helper::Default(text) if to_text(&value).is_empty() => Value::String(text.clone()),
+            Helper::Default(_) => value,
+            Helper::Truncate(length) => {
+                let text = to_text(&value);
+                if text.chars().count() > *length {
+                    let mut truncated = text
+                        .chars()
+                        .take(length.saturating_sub(1))
+                        .collect::<String>();
+                    truncated.push('…');
+                    Value::String(truncated)
+                } else {
+                    Value::String(text)
+                }
+            }
+            Helper::Upper => Value::String(to_text(&value).to_uppercase()),
+            Helper::Lower => Value::String(to_text(&value).to_lowercase()),
+            Helper::Markup => Value::String(escape_markup(&to_text(&value))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Text(String),
+    Field {
+        path: Vec<String>,
+        helpers: Vec<Helper>,
+    },
+}
+
+/// Output template like `{{artist}} - {{title|truncate:30}} [{{position|duration}}]`. Fields
+/// are looked up in the JSON printed by `follow`, nested fields are separated by dots.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut segments = Vec::new();
+        let mut rest = s;
+
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                segments.push(Segment::Text(rest[..start].to_owned()));
+            }
+            let end = rest[start..]
+                .find("}}")
+                .ok_or_else(|| format!("unclosed {{{{ in {:?}", s))?;
+            let expression = &rest[start + 2..start + end];
+
+            let mut parts = expression.split('|');
+            let field = parts.next().unwrap_or_default().trim();
+            if field.is_empty() {
+                return Err(format!("missing field name in {{{{{}}}}}", expression));
+            }
+            segments.push(Segment::Field {
+                path: field.split('.').map(ToOwned::to_owned).collect(),
+                helpers: parts.map(Helper::parse).collect::<Result<_, _>>()?,
+            });
+
+            rest = &rest[start + end + 2..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Text(rest.to_owned()));
+        }
+
+        Ok(Self { segments })
+    }
+
+    pub fn render(&self, data: &Value) -> String {
+        let mut output = String::new();
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Text(text) => output.push_str(text),
+                Segment::Field { path, helpers } => {
+                    let value = path
+                        .iter()
+                        .try_fold(data, |value, key| value.get(key))
+                        .cloned()
+                        .unwrap_or(Value::Null);
+                    let value = helpers
+                        .iter()
+                        .fold(value, |value, helper| helper.apply(value));
+                    output.push_str(&to_text(&value));
+                }
+            }
+        }
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn render(template: &str, data: &Value) -> String {
+        Template::parse(template).unwrap().render(data)
+    }
+
+    #[test]
+    fn parses_fields_and_helpers() {
+        let template = Template::parse("{{ metadata.xesam:title | truncate: 5 |upper}}!").unwrap();
+        assert_eq!(
+            template.segments,
+            [
+                Segment::Field {
+                    path: vec!["metadata".to_owned(), "xesam:title".to_owned()],
+                    helpers: vec![Helper::Truncate(5), Helper::Upper],
+                },
+                Segment::Text("!".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        assert_eq!(
+            Template::parse("{{title"),
+            Err("unclosed {{ in \"{{title\"".to_owned())
+        );
+        assert!(Template::parse("{{}}").is_err());
+        assert!(Template::parse("{{|upper}}").is_err());
+        assert!(Template::parse("{{title|bold}}").is_err());
+        assert!(Template::parse("{{title|truncate:many}}").is_err());
+        assert!(Template::parse("{{title|upper:1}}").is_err());
+    }
+
+    #[test]
+    fn renders_fields() {
+        let data = json!({
+            "artist": ["Foo", "Bar"],
+            "title": "Baz",
+            "position": 83_000_000,
+            "volume": 0.5,
+            "metadata": {"xesam:url": "file:///baz.mp3"},
+        });
+
+        assert_eq!(render("{{artist}} - {{title}}", &data), "Foo, Bar - Baz");
+        assert_eq!(render("[{{position|duration}}]", &data), "[1:23]");
+        assert_eq!(render("{{volume}}", &data), "0.5");
+        assert_eq!(render("{{metadata.xesam:url}}", &data), "file:///baz.mp3");
+        // single braces are plain text
+        assert_eq!(render("{title} {{title}}", &data), "{title} Baz");
+    }
+
+    #[test]
+    fn renders_unknown_fields_as_empty() {
+        let data = json!({"title": "Baz", "album": null});
+
+        assert_eq!(render("{{album}}|{{nothing.here}}|", &data), "||");
+        assert_eq!(render("{{album|default:-}}", &data), "-");
+        assert_eq!(render("{{title|default:-}}", &data), "Baz");
+    }
+
+    #[test]
+    fn applies_helpers_in_order() {
+        let data = json!({"title": "Rock & <Roll>", "length": 3_723_000_000_i64});
+
+        assert_eq!(render("{{title|markup}}", &data), "Rock &amp; &lt;Roll&gt;");
+        assert_eq!(render("{{title|truncate:6|upper}}", &data), "ROCK …");
+        assert_eq!(
+            render("{{title|lower|truncate:20}}", &data),
+            "rock & <roll>"
+        );
+        assert_eq!(render("{{length|duration}}", &data), "1:02:03");
+        assert_eq!(render("{{title|duration}}", &data), "Rock & <Roll>");
+        assert_eq!(format_duration(-5_000_000), "-0:05");
+    }
+}
@@ -1,8 +1,10 @@
+use crate::bus;
 use crate::constants::{ACTIVE_PLAYER_PROPERTY, WELL_KNOWN_NAME, WELL_KNOWN_PATH};
+use crate::follow::player_state;
+use crate::format::Template;
 use dbus::nonblock::Proxy;
 use dbus::nonblock::SyncConnection;
 use dbus::nonblock::stdintf::org_freedesktop_dbus::Properties;
-use dbus_tokio::connection;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -23,15 +25,17 @@ pub async fn get_active_player_impl(
     Ok((name, channels))
 }
 
-pub async fn get_active_player() -> Result<(), dbus::Error> {
-    let (resource, connection) = connection::new_session_sync()?;
-    tokio::spawn(async {
-        let err = resource.await;
-        panic!("Lost connection to D-Bus: {}", err);
-    });
+pub async fn get_active_player(format: Option<Template>) -> Result<(), dbus::Error> {
+    let connection = bus::connect_once()?;
 
-    let (player, _) = get_active_player_impl(connection).await?;
+    let (player, _) = get_active_player_impl(connection.clone()).await?;
 
-    println!("{}", player);
+    match format {
+        Some(format) => println!(
+            "{}",
+            format.render(&player_state(player, connection).await?)
+        ),
+        None => println!("{}", player),
+    }
     Ok(())
 }
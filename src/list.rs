@@ -0,0 +1,65 @@
+use crate::bus;
+use crate::constants::{PLAYERS_PROPERTY, WELL_KNOWN_NAME, WELL_KNOWN_PATH};
+use dbus::nonblock::Proxy;
+use dbus::nonblock::SyncConnection;
+use dbus::nonblock::stdintf::org_freedesktop_dbus::Properties;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub async fn get_players_impl(
+    connection: Arc<SyncConnection>,
+) -> Result<Vec<(String, Vec<String>, bool)>, dbus::Error> {
+    let proxy = Proxy::new(
+        WELL_KNOWN_NAME,
+        WELL_KNOWN_PATH,
+        Duration::from_secs(5),
+        connection,
+    );
+
+    proxy
+        .get::<Vec<(String, Vec<String>, bool)>>(WELL_KNOWN_NAME, PLAYERS_PROPERTY)
+        .await
+}
+
+pub async fn list_players(as_json: bool) -> Result<(), dbus::Error> {
+    let connection = bus::connect_once()?;
+
+    let players = get_players_impl(connection).await?;
+
+    if as_json {
+        let players = players
+            .into_iter()
+            .enumerate()
+            .map(|(position, (name, channels, playing))| {
+                json!({
+                    "position": position,
+                    "player": name,
+                    "channels": channels,
+                    "playing": playing,
+                })
+            })
+            .collect::<Vec<_>>();
+        println!("{}", serde_json::Value::Array(players));
+    } else {
+        let width = players
+            .iter()
+            .map(|(name, _, _)| name.len())
+            .max()
+            .unwrap_or_default()
+            .max("PLAYER".len());
+
+        println!("{:<3} {:<7} {:<width$} CHANNELS", "#", "PLAYING", "PLAYER");
+        for (position, (name, channels, playing)) in players.iter().enumerate() {
+            println!(
+                "{:<3} {:<7} {:<width$} {}",
+                position,
+                if *playing { "yes" } else { "no" },
+                name,
+                channels.join(", "),
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -1,32 +1,116 @@
+mod art;
+mod bus;
+mod config;
 mod constants;
 mod control;
 mod daemon;
 mod follow;
+mod format;
 mod get;
+mod list;
 mod players;
 mod players_queue;
 mod shift;
+mod state;
+mod tracker;
 
-use crate::control::control;
-use crate::daemon::Daemon;
+use crate::art::ArtCache;
+use crate::control::{
+    LoopStatus, Switch, Volume, control, open_uri, parse_interval, parse_position, parse_time,
+    parse_volume, seek, set_loop, set_position, set_shuffle, set_volume,
+};
+use crate::daemon::run_daemon;
 use crate::follow::follow_changes;
+use crate::format::Template;
 use crate::get::get_active_player;
-use crate::shift::{next_player, previous_player};
+use crate::list::list_players;
+use crate::shift::{next_player, pin_player, previous_player, select_player, unpin_player};
 use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+use std::time::Duration;
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Subcommand)]
+#[derive(Debug, Clone, PartialEq, Subcommand)]
 enum Command {
-    Daemon,
-    Get,
-    Follow,
+    Daemon {
+        /// Config file, `$XDG_CONFIG_HOME/player_watcher/config.toml` by default
+        #[arg(long)]
+        config: Option<PathBuf>,
+    },
+    Get {
+        /// Print state of the player using template, e.g. `{{artist}} - {{title}}`
+        #[arg(long, value_parser = Template::parse)]
+        format: Option<Template>,
+    },
+    /// List all tracked players in queue order
+    List {
+        /// Print as JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    Follow {
+        /// Print each change using template instead of JSON, e.g. `{{artist}} - {{title}}`
+        #[arg(long, value_parser = Template::parse)]
+        format: Option<Template>,
+        /// Also print the state periodically while playing, e.g. `1s`, to keep position current
+        #[arg(long, value_parser = parse_interval)]
+        tick: Option<Duration>,
+        /// Include all metadata reported by the player, under `metadata` with original keys
+        #[arg(long)]
+        metadata: bool,
+        /// Print once changes stop for this long, e.g. `200ms`, instead of each of them
+        #[arg(long, value_parser = parse_interval)]
+        debounce: Option<Duration>,
+        /// Keep local copies of cover art in this directory and print their paths as `art_path`
+        #[arg(long, value_name = "DIR")]
+        cache_art: Option<PathBuf>,
+        /// Image to use as `art_path` when the player reports no cover art
+        #[arg(long, value_name = "FILE", requires = "cache_art")]
+        art_fallback: Option<PathBuf>,
+    },
+    /// Make the next player active, unpinning the current one
     Shift,
+    /// Make the previous player active, unpinning the current one
     Unshift,
+    /// Make the given player active, matching by name or its fragment, unpinning the current one
+    Select {
+        /// e.g. `firefox` for `org.mpris.MediaPlayer2.firefox.instance_1_45`
+        name: String,
+    },
+    /// Keep the active player until it disappears, even if others start playing
+    Pin,
+    /// Let the active player change automatically again
+    Unpin,
     Next,
     Previous,
     Pause,
     PlayPause,
     Stop,
     Play,
+    /// Seek relatively, e.g. `+10s`, `-5s` or `+1:00`
+    Seek {
+        #[arg(value_parser = parse_time, allow_hyphen_values = true)]
+        offset: i64,
+    },
+    /// Jump to position, e.g. `1:23` or `83s`
+    Position {
+        #[arg(value_parser = parse_position)]
+        position: i64,
+    },
+    /// Set volume, e.g. `0.5` or `50%`, or change it, e.g. `+5%` or `-0.1`
+    Volume {
+        #[arg(value_parser = parse_volume, allow_hyphen_values = true)]
+        volume: Volume,
+    },
+    Shuffle {
+        shuffle: Switch,
+    },
+    Loop {
+        status: LoopStatus,
+    },
+    /// Open URI in the active player
+    Open {
+        uri: String,
+    },
 }
 
 #[derive(Parser, Debug)]
@@ -41,17 +125,39 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
 
     match args.command {
-        Command::Daemon => Daemon::new().await?.run().await?,
-        Command::Get => get_active_player().await?,
-        Command::Follow => follow_changes().await?,
+        Command::Daemon { config } => run_daemon(config).await?,
+        Command::Get { format } => get_active_player(format).await?,
+        Command::List { json } => list_players(json).await?,
+        Command::Follow {
+            format,
+            tick,
+            metadata,
+            debounce,
+            cache_art,
+            art_fallback,
+        } => {
+            let art_cache = cache_art
+                .map(|dir| ArtCache::new(dir, art_fallback))
+                .transpose()?;
+            follow_changes(format, tick, metadata, debounce, art_cache).await?
+        }
         Command::Shift => next_player().await?,
         Command::Unshift => previous_player().await?,
+        Command::Select { name } => select_player(name).await?,
+        Command::Pin => pin_player().await?,
+        Command::Unpin => unpin_player().await?,
         Command::PlayPause => control("PlayPause").await?,
         Command::Next => control("Next").await?,
         Command::Previous => control("Previous").await?,
         Command::Pause => control("Pause").await?,
         Command::Stop => control("Stop").await?,
         Command::Play => control("Play").await?,
+        Command::Seek { offset } => seek(offset).await?,
+        Command::Position { position } => set_position(position).await?,
+        Command::Volume { volume } => set_volume(volume).await?,
+        Command::Shuffle { shuffle } => set_shuffle(shuffle).await?,
+        Command::Loop { status } => set_loop(status).await?,
+        Command::Open { uri } => open_uri(uri).await?,
     }
 
     Ok(())
@@ -1,22 +1,72 @@
+use crate::constants::MPRIS_PREFIX;
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
+/// name of the player without `MPRIS_PREFIX`, e.g. `firefox.instance_1_45`
+pub fn short_name(name: &str) -> &str {
+    name.strip_prefix(MPRIS_PREFIX).unwrap_or(name)
+}
+
+/// short name without the instance suffix, e.g. `firefox`
+pub fn player_identity(name: &str) -> &str {
+    let name = short_name(name);
+    match name.find(".instance") {
+        Some(idx) => &name[..idx],
+        None => name,
+    }
+}
+
 #[derive(Default)]
 pub struct Players {
     players: HashMap<Arc<str>, HashSet<Arc<str>>>,
     rev_players: HashMap<Arc<str>, Arc<str>>,
+    /// channels which sent player signals, but own no tracked name, so they aren't looked for again
+    /// until they become an owner of some
+    unknown_channels: HashSet<Arc<str>>,
 }
 
 impl Players {
     /// return whether this is a new name
     pub fn add(&mut self, name: Arc<str>, channels: Vec<Arc<str>>) -> bool {
+        let is_new = !self.players.contains_key(&name);
+        self.set_channels(name, channels);
+        is_new
+    }
+
+    /// Replace channels of `name`, e.g. when it gets a new or queued owner.
+    pub fn set_channels(&mut self, name: Arc<str>, channels: Vec<Arc<str>>) {
+        if let Some(old_channels) = self.players.remove(&name) {
+            for channel in old_channels {
+                if self.rev_players.get(&channel) == Some(&name) {
+                    self.rev_players.remove(&channel);
+                }
+            }
+        }
+
         for channel in &channels {
             self.rev_players.insert(channel.clone(), name.clone());
+            self.unknown_channels.remove(channel);
         }
+        self.players.insert(name, HashSet::from_iter(channels));
+    }
+
+    /// Forget channel which left the bus. Return the player it belonged to.
+    pub fn remove_channel(&mut self, channel: &str) -> Option<Arc<str>> {
+        self.unknown_channels.remove(channel);
 
-        self.players
-            .insert(name.clone(), HashSet::from_iter(channels))
-            .is_none()
+        let name = self.rev_players.remove(channel)?;
+        if let Some(channels) = self.players.get_mut(&name) {
+            channels.remove(channel);
+        }
+        Some(name)
+    }
+
+    pub fn is_unknown_channel(&self, channel: &str) -> bool {
+        self.unknown_channels.contains(channel)
+    }
+
+    pub fn add_unknown_channel(&mut self, channel: Arc<str>) {
+        self.unknown_channels.insert(channel);
     }
 
     pub fn remove(&mut self, name: &str) {
@@ -27,6 +77,10 @@ impl Players {
         }
     }
 
+    pub fn contains(&self, name: &str) -> bool {
+        self.players.contains_key(name)
+    }
+
     pub fn find_by_channel(&self, channel: &str) -> Option<Arc<str>> {
         self.rev_players.get(channel).cloned()
     }
@@ -56,3 +110,59 @@ impl Players {
         self.players.get(&name)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arcs(names: &[&str]) -> Vec<Arc<str>> {
+        names.iter().map(|&n| Arc::from(n)).collect()
+    }
+
+    #[test]
+    fn follows_owner_changes() {
+        let mut players = Players::default();
+        let vlc: Arc<str> = Arc::from("org.mpris.MediaPlayer2.vlc");
+        assert!(players.add(vlc.clone(), arcs(&[":1.1"])));
+
+        // queued owner
+        players.set_channels(vlc.clone(), arcs(&[":1.1", ":1.2"]));
+        assert_eq!(players.find_by_channel(":1.2"), Some(vlc.clone()));
+
+        // handed over
+        players.set_channels(vlc.clone(), arcs(&[":1.2"]));
+        assert_eq!(players.find_by_channel(":1.1"), None);
+        assert_eq!(players.find_by_channel(":1.2"), Some(vlc.clone()));
+
+        assert_eq!(players.remove_channel(":1.2"), Some(vlc.clone()));
+        assert_eq!(players.find_by_channel(":1.2"), None);
+        assert!(players.contains(&vlc));
+        assert!(!players.add(vlc, arcs(&[":1.3"])));
+    }
+
+    #[test]
+    fn keeps_channels_shared_with_other_player() {
+        let mut players = Players::default();
+        let vlc: Arc<str> = Arc::from("org.mpris.MediaPlayer2.vlc");
+        let mpv: Arc<str> = Arc::from("org.mpris.MediaPlayer2.mpv");
+        players.add(vlc.clone(), arcs(&[":1.1"]));
+        players.add(mpv.clone(), arcs(&[":1.1"]));
+
+        players.set_channels(vlc, arcs(&[":1.2"]));
+        assert_eq!(players.find_by_channel(":1.1"), Some(mpv));
+    }
+
+    #[test]
+    fn forgets_unknown_channels() {
+        let mut players = Players::default();
+        players.add_unknown_channel(Arc::from(":1.5"));
+        assert!(players.is_unknown_channel(":1.5"));
+
+        players.set_channels(Arc::from("org.mpris.MediaPlayer2.vlc"), arcs(&[":1.5"]));
+        assert!(!players.is_unknown_channel(":1.5"));
+
+        players.add_unknown_channel(Arc::from(":1.6"));
+        players.add(Arc::from("org.mpris.MediaPlayer2.mpv"), arcs(&[":1.7"]));
+        assert!(players.is_unknown_channel(":1.6"));
+    }
+}
@@ -1,10 +1,15 @@
-use std::collections::HashSet;
+use crate::config::PlayerRules;
+use crate::players::{player_identity, short_name};
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
 #[derive(Default)]
 pub struct PlayersQueue {
     playing: HashSet<Arc<str>>,
     queue: Vec<Arc<str>>,
+    /// active player is kept in front regardless of playback status changes
+    pinned: bool,
+    rules: HashMap<Arc<str>, PlayerRules>,
 }
 
 impl PlayersQueue {
@@ -12,6 +17,17 @@ impl PlayersQueue {
         self.queue.first().cloned()
     }
 
+    /// iterate over players in queue order, with their playing state
+    pub fn iter(&self) -> impl Iterator<Item = (&Arc<str>, bool)> {
+        self.queue
+            .iter()
+            .map(|name| (name, self.playing.contains(name)))
+    }
+
+    pub fn is_playing(&self, player: &str) -> bool {
+        self.playing.contains(player)
+    }
+
     pub fn set_playing(&mut self, player: Arc<str>, playing: bool) {
         if playing {
             self.playing.insert(player);
@@ -20,28 +36,162 @@ impl PlayersQueue {
         }
     }
 
-    pub fn add_player(&mut self, name: Arc<str>, playing: bool) {
-        if self.queue.is_empty() || playing || !self.playing.contains(&self.queue[0]) {
-            self.queue.insert(0, name.clone());
+    pub fn is_pinned(&self) -> bool {
+        self.pinned
+    }
+
+    /// return the pinned player
+    pub fn pin(&mut self) -> Option<Arc<str>> {
+        self.pinned = !self.queue.is_empty();
+        self.get_active()
+    }
+
+    /// return whether active player changed
+    #[must_use]
+    pub fn unpin(&mut self) -> bool {
+        self.pinned = false;
+        self.activate_best_playing()
+    }
+
+    fn rules(&self, name: &str) -> PlayerRules {
+        self.rules.get(name).copied().unwrap_or_default()
+    }
+
+    /// whether `name` is playing and should stay ahead of a playing player with `priority`
+    fn outranks(&self, name: &Arc<str>, priority: i32) -> bool {
+        self.playing.contains(name) && self.rules(name).priority > priority
+    }
+
+    /// where a player with `priority`, which just started playing, should be placed
+    fn placement(&self, priority: i32) -> usize {
+        let idx = self
+            .queue
+            .iter()
+            .position(|n| !self.outranks(n, priority))
+            .unwrap_or(self.queue.len());
+
+        if self.pinned { idx.max(1) } else { idx }
+    }
+
+    /// index of the playing player with highest priority, which may become active automatically
+    fn best_playing(&self, above_priority: Option<i32>) -> Option<usize> {
+        self.queue
+            .iter()
+            .enumerate()
+            .filter(|(_, n)| self.playing.contains(*n) && self.rules(n).auto_activate)
+            .filter(|(_, n)| above_priority.is_none_or(|p| self.rules(n).priority > p))
+            .min_by_key(|(idx, n)| (-self.rules(n).priority, *idx))
+            .map(|(idx, _)| idx)
+    }
+
+    /// If the active player is not playing, activate the playing one with highest priority.
+    /// Return whether active player changed.
+    fn activate_best_playing(&mut self) -> bool {
+        if self.pinned || self.queue.first().is_none_or(|n| self.playing.contains(n)) {
+            return false;
+        }
+
+        if let Some(idx) = self.best_playing(None) {
+            self.queue[0..=idx].rotate_right(1);
+            true
         } else {
-            self.queue.insert(1, name.clone());
+            false
         }
+    }
+
+    pub fn add_player(&mut self, name: Arc<str>, playing: bool, rules: PlayerRules) {
+        self.rules.insert(name.clone(), rules);
+
+        let idx = if self.queue.is_empty() {
+            0
+        } else if playing && rules.auto_activate {
+            self.placement(rules.priority)
+        } else if self.pinned || self.playing.contains(&self.queue[0]) {
+            1
+        } else {
+            0
+        };
+        self.queue.insert(idx, name.clone());
 
         self.set_playing(name, playing);
     }
 
+    /// Add player, which isn't playing, at `idx`, e.g. to restore saved order.
+    pub fn insert_player(&mut self, idx: usize, name: Arc<str>, rules: PlayerRules) {
+        let idx = if self.pinned { idx.max(1) } else { idx };
+        self.rules.insert(name.clone(), rules);
+        self.queue.insert(idx.min(self.queue.len()), name);
+    }
+
+    /// Move playing players ahead of the others, as if each of them was promoted, e.g. once their
+    /// status is read after restoring saved order. Higher priority goes first, then lower `rank`.
+    /// Return whether active player changed.
+    #[must_use]
+    pub fn promote_playing(&mut self, rank: impl Fn(&str) -> usize) -> bool {
+        let active = self.get_active();
+        let start = if self.pinned { 1 } else { 0 }.min(self.queue.len());
+
+        let rules = &self.rules;
+        let priority = |name: &str| rules.get(name).copied().unwrap_or_default().priority;
+        let (mut playing, rest): (Vec<_>, Vec<_>) = self.queue.drain(start..).partition(|n| {
+            self.playing.contains(n) && rules.get(n).copied().unwrap_or_default().auto_activate
+        });
+        playing.sort_by_key(|n| (-priority(n), rank(n)));
+        self.queue.extend(playing);
+        self.queue.extend(rest);
+
+        self.get_active() != active
+    }
+
+    /// Change rules of already added players, e.g. after config reload. Return whether active
+    /// player changed.
+    #[must_use]
+    pub fn set_rules(&mut self, rules: impl IntoIterator<Item = (Arc<str>, PlayerRules)>) -> bool {
+        self.rules.extend(rules);
+
+        let Some(active) = self.get_active() else {
+            return false;
+        };
+        if self.pinned || !self.playing.contains(&active) {
+            return self.activate_best_playing();
+        }
+
+        // active player keeps playing, but may be outranked now
+        let priority = self.rules(&active).priority;
+        if let Some(idx) = self.best_playing(Some(priority)) {
+            self.queue[0..=idx].rotate_right(1);
+            true
+        } else {
+            false
+        }
+    }
+
     pub fn remove_player(&mut self, name: &str) {
+        if self
+            .get_active()
+            .is_some_and(|active| active.as_ref() == name)
+        {
+            self.pinned = false;
+        }
         self.queue.retain(|e| e.as_ref() != name);
         self.playing.remove(name);
+        self.rules.remove(name);
     }
 
     #[must_use]
     pub fn promote(&mut self, name: Arc<str>) -> bool {
         if let Some(idx) = self.queue.iter().rposition(|n| *n == name) {
-            self.set_playing(name, true);
-            if idx > 0 {
-                self.queue[0..=idx].rotate_right(1);
-                true
+            self.set_playing(name.clone(), true);
+
+            let rules = self.rules(&name);
+            if !rules.auto_activate {
+                return false;
+            }
+
+            let target = self.placement(rules.priority);
+            if target < idx {
+                self.queue[target..=idx].rotate_right(1);
+                target == 0
             } else {
                 false
             }
@@ -54,24 +204,51 @@ impl PlayersQueue {
     #[must_use]
     pub fn demote(&mut self, name: Arc<str>) -> bool {
         self.set_playing(name, false);
-        if let Some(idx) = self.queue.iter().position(|n| self.playing.contains(n)) {
-            self.queue[0..=idx].rotate_right(1);
-            true
-        } else {
-            false
+        self.activate_best_playing()
+    }
+
+    /// Move the player best matching `pattern` to the front. Full name, short name or identity
+    /// must match exactly, otherwise the first player containing `pattern` in its short name is
+    /// chosen. A pinned player is unpinned, as another one is chosen explicitly. Return the
+    /// selected player, `None` if none matches or `pattern` is empty.
+    pub fn select(&mut self, pattern: &str) -> Option<Arc<str>> {
+        if pattern.is_empty() {
+            return None;
         }
+        let lowercase = pattern.to_lowercase();
+        let idx = self
+            .queue
+            .iter()
+            .position(|n| {
+                n.as_ref() == pattern || short_name(n) == pattern || player_identity(n) == pattern
+            })
+            .or_else(|| {
+                self.queue
+                    .iter()
+                    .position(|n| short_name(n).to_lowercase().contains(&lowercase))
+            })?;
+
+        self.pinned = false;
+        self.queue[0..=idx].rotate_right(1);
+        self.get_active()
     }
 
-    pub fn shift(&mut self) {
+    /// Unpin and return the new active player
+    pub fn shift(&mut self) -> Option<Arc<str>> {
+        self.pinned = false;
         if !self.queue.is_empty() {
             self.queue.rotate_left(1);
         }
+        self.get_active()
     }
 
-    pub fn unshift(&mut self) {
+    /// Unpin and return the new active player
+    pub fn unshift(&mut self) -> Option<Arc<str>> {
+        self.pinned = false;
         if !self.queue.is_empty() {
             self.queue.rotate_right(1);
         }
+        self.get_active()
     }
 
     // async fn check_all_if_playing(
@@ -86,3 +263,136 @@ impl PlayersQueue {
     // }
     //
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(names: &[&str]) -> PlayersQueue {
+        let mut queue = PlayersQueue::default();
+        for name in names.iter().rev() {
+            queue.add_player(Arc::from(*name), false, PlayerRules::default());
+        }
+        queue
+    }
+
+    fn order(queue: &PlayersQueue) -> Vec<&str> {
+        queue.iter().map(|(name, _)| name.as_ref()).collect()
+    }
+
+    fn rules(priority: i32) -> PlayerRules {
+        PlayerRules {
+            priority,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn keeps_playing_players_with_higher_priority_ahead() {
+        let mut queue = PlayersQueue::default();
+        queue.add_player(Arc::from("spotify"), true, rules(2));
+        queue.add_player(Arc::from("mpv"), true, rules(1));
+        queue.add_player(Arc::from("vlc"), false, rules(3));
+        assert_eq!(order(&queue), ["spotify", "vlc", "mpv"]);
+
+        // outranks both, so it becomes active
+        assert!(queue.promote(Arc::from("vlc")));
+        assert_eq!(order(&queue), ["vlc", "spotify", "mpv"]);
+
+        queue.add_player(Arc::from("firefox"), true, rules(0));
+        assert_eq!(order(&queue), ["vlc", "spotify", "mpv", "firefox"]);
+
+        // the playing one with highest priority takes over
+        assert!(queue.demote(Arc::from("vlc")));
+        assert_eq!(order(&queue), ["spotify", "vlc", "mpv", "firefox"]);
+        assert!(!queue.demote(Arc::from("mpv")));
+
+        assert!(queue.demote(Arc::from("spotify")));
+        assert_eq!(order(&queue), ["firefox", "spotify", "vlc", "mpv"]);
+    }
+
+    #[test]
+    fn promotes_playing_players_by_priority_and_rank() {
+        let mut queue = queue(&["vlc", "mpv", "spotify", "firefox"]);
+        queue.set_playing(Arc::from("spotify"), true);
+        queue.set_playing(Arc::from("firefox"), true);
+        queue.set_playing(Arc::from("mpv"), true);
+        let _ = queue.set_rules([(Arc::from("firefox"), rules(1))]);
+        let _ = queue.set_rules([(
+            Arc::from("mpv"),
+            PlayerRules {
+                auto_activate: false,
+                ..Default::default()
+            },
+        )]);
+        assert_eq!(order(&queue), ["firefox", "vlc", "mpv", "spotify"]);
+
+        let rank = |name: &str| {
+            ["spotify", "vlc", "firefox"]
+                .iter()
+                .position(|n| *n == name)
+        };
+        assert!(!queue.promote_playing(|name| rank(name).unwrap_or(0)));
+        assert_eq!(order(&queue), ["firefox", "spotify", "vlc", "mpv"]);
+
+        // the pinned player stays active even though it's not playing
+        queue.set_playing(Arc::from("firefox"), false);
+        queue.set_playing(Arc::from("vlc"), true);
+        queue.pin();
+        assert!(!queue.promote_playing(|name| rank(name).unwrap_or(0)));
+        assert_eq!(order(&queue), ["firefox", "spotify", "vlc", "mpv"]);
+    }
+
+    #[test]
+    fn selects_exact_match_before_fragment() {
+        const FIREFOX: &str = "org.mpris.MediaPlayer2.firefox.instance_1_45";
+        let mut queue = queue(&[
+            "org.mpris.MediaPlayer2.spotify",
+            FIREFOX,
+            "org.mpris.MediaPlayer2.vlc-nightly",
+            "org.mpris.MediaPlayer2.vlc",
+        ]);
+
+        assert_eq!(queue.select("firefox").as_deref(), Some(FIREFOX));
+        assert_eq!(
+            queue.select("spotify").as_deref(),
+            Some("org.mpris.MediaPlayer2.spotify")
+        );
+        assert_eq!(queue.select("FireFox.Instance").as_deref(), Some(FIREFOX));
+        assert_eq!(queue.select(FIREFOX).as_deref(), Some(FIREFOX));
+
+        // the nightly one contains `vlc` and comes first, but the exact match wins
+        assert_eq!(
+            queue.select("vlc").as_deref(),
+            Some("org.mpris.MediaPlayer2.vlc")
+        );
+        assert_eq!(
+            queue.select("Nightly").as_deref(),
+            Some("org.mpris.MediaPlayer2.vlc-nightly")
+        );
+
+        queue.pin();
+        assert_eq!(queue.select("mpv"), None);
+        assert_eq!(queue.select(""), None);
+        assert!(queue.is_pinned());
+        assert_eq!(
+            queue.get_active().as_deref(),
+            Some("org.mpris.MediaPlayer2.vlc-nightly")
+        );
+    }
+
+    #[test]
+    fn unpins_on_shift() {
+        let mut queue = queue(&["vlc", "mpv", "spotify"]);
+        assert_eq!(order(&queue), ["vlc", "mpv", "spotify"]);
+        queue.pin();
+
+        assert_eq!(queue.shift().as_deref(), Some("mpv"));
+        assert!(!queue.is_pinned());
+        assert_eq!(order(&queue), ["mpv", "spotify", "vlc"]);
+
+        // the shifted to player isn't kept when another one starts playing
+        assert!(queue.promote(Arc::from("vlc")));
+        assert_eq!(queue.get_active().as_deref(), Some("vlc"));
+    }
+}
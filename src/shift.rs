@@ -1,15 +1,14 @@
-use crate::constants::{SHIFT_METHOD, UNSHIFT_METHOD, WELL_KNOWN_NAME, WELL_KNOWN_PATH};
-use dbus::nonblock::{MethodReply, Proxy};
-use dbus_tokio::connection;
+use crate::bus;
+use crate::constants::{
+    PIN_METHOD, SELECT_METHOD, SHIFT_METHOD, UNPIN_METHOD, UNSHIFT_METHOD, WELL_KNOWN_NAME,
+    WELL_KNOWN_PATH,
+};
+use dbus::arg::AppendAll;
+use dbus::nonblock::Proxy;
 use std::time::Duration;
 
-pub async fn next_player() -> Result<(), dbus::Error> {
-    let (resource, connection) = connection::new_session_sync()?;
-
-    tokio::spawn(async {
-        let err = resource.await;
-        panic!("Lost connection to D-Bus: {}", err);
-    });
+async fn change_active<A: AppendAll>(method: &str, args: A) -> Result<(), dbus::Error> {
+    let connection = bus::connect_once()?;
 
     let proxy = Proxy::new(
         WELL_KNOWN_NAME,
@@ -18,27 +17,28 @@ pub async fn next_player() -> Result<(), dbus::Error> {
         connection,
     );
 
-    let _: MethodReply<()> = proxy.method_call(WELL_KNOWN_NAME, SHIFT_METHOD, ());
+    let (player,): (String,) = proxy.method_call(WELL_KNOWN_NAME, method, args).await?;
 
+    println!("{}", player);
     Ok(())
 }
 
-pub async fn previous_player() -> Result<(), dbus::Error> {
-    let (resource, connection) = connection::new_session_sync()?;
+pub async fn next_player() -> Result<(), dbus::Error> {
+    change_active(SHIFT_METHOD, ()).await
+}
 
-    tokio::spawn(async {
-        let err = resource.await;
-        panic!("Lost connection to D-Bus: {}", err);
-    });
+pub async fn previous_player() -> Result<(), dbus::Error> {
+    change_active(UNSHIFT_METHOD, ()).await
+}
 
-    let proxy = Proxy::new(
-        WELL_KNOWN_NAME,
-        WELL_KNOWN_PATH,
-        Duration::from_secs(5),
-        connection,
-    );
+pub async fn select_player(name: String) -> Result<(), dbus::Error> {
+    change_active(SELECT_METHOD, (name,)).await
+}
 
-    let _: MethodReply<()> = proxy.method_call(WELL_KNOWN_NAME, UNSHIFT_METHOD, ());
+pub async fn pin_player() -> Result<(), dbus::Error> {
+    change_active(PIN_METHOD, ()).await
+}
 
-    Ok(())
+pub async fn unpin_player() -> Result<(), dbus::Error> {
+    change_active(UNPIN_METHOD, ()).await
 }
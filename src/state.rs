@@ -0,0 +1,202 @@
+use crate::config::xdg_dir;
+use crate::players::player_identity;
+use crate::tracker::Snapshot;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Players which left are remembered while there are at most this many players in total, those
+/// last in order are forgotten first. Present players are always kept.
+const MAX_SAVED_PLAYERS: usize = 64;
+
+/// Order of players and the pinned one, kept across restarts of the daemon. Players are
+/// identified by `player_identity`, as instance suffixes change between runs.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct State {
+    order: Vec<String>,
+    pinned: Option<String>,
+}
+
+impl State {
+    pub fn default_path() -> Option<PathBuf> {
+        let state_home = xdg_dir("XDG_STATE_HOME", ".local/state")?;
+
+        Some(state_home.join("player_watcher").join("state"))
+    }
+
+    /// Load state saved at `path`. Missing file means no saved state.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("invalid state {}: {}", path.display(), e).into())
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        // write under temporary name, so the state is never left half written
+        let partial = path.with_extension("part");
+        fs::write(&partial, toml::to_string(self)?)?;
+        fs::rename(&partial, path)?;
+
+        Ok(())
+    }
+
+    /// Position of player `name` in saved order.
+    pub fn rank(&self, name: &str) -> Option<usize> {
+        let identity = player_identity(name);
+        self.order.iter().position(|saved| saved == identity)
+    }
+
+    pub fn is_pinned(&self, name: &str) -> bool {
+        self.pinned.as_deref() == Some(player_identity(name))
+    }
+
+    /// Take order of players from `snapshot`, players which aren't present keep their place
+    /// after the player which preceded them. Return whether anything changed.
+    pub fn update(&mut self, snapshot: &Snapshot) -> bool {
+        let mut order = Vec::<String>::new();
+        for (name, _, _) in &snapshot.players {
+            let identity = player_identity(name);
+            if !order.iter().any(|saved| saved == identity) {
+                order.push(identity.to_owned());
+            }
+        }
+
+        let present = order.len();
+
+        let mut idx = 0;
+        for identity in &self.order {
+            match order.iter().position(|saved| saved == identity) {
+                Some(present) => idx = present + 1,
+                None => {
+                    order.insert(idx, identity.clone());
+                    idx += 1;
+                }
+            }
+        }
+
+        let mut excess = order.len().saturating_sub(MAX_SAVED_PLAYERS.max(present));
+        let mut idx = order.len();
+        while excess > 0 {
+            idx -= 1;
+            if !snapshot
+                .players
+                .iter()
+                .any(|(name, _, _)| player_identity(name) == order[idx])
+            {
+                order.remove(idx);
+                excess -= 1;
+            }
+        }
+
+        let pinned = snapshot
+            .pinned
+            .then(|| {
+                snapshot
+                    .active()
+                    .map(|name| player_identity(name).to_owned())
+            })
+            .flatten();
+
+        let changed = order != self.order || pinned != self.pinned;
+        self.order = order;
+        self.pinned = pinned;
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn snapshot(names: &[&str], pinned: bool) -> Snapshot {
+        Snapshot {
+            players: names
+                .iter()
+                .map(|&name| (Arc::from(name), Vec::new(), false))
+                .collect(),
+            pinned,
+        }
+    }
+
+    #[test]
+    fn keeps_place_of_players_which_left() {
+        let mut state = State::default();
+        assert!(state.update(&snapshot(
+            &[
+                "org.mpris.MediaPlayer2.spotify",
+                "org.mpris.MediaPlayer2.firefox.instance_1_45",
+                "org.mpris.MediaPlayer2.firefox.instance_1_46",
+                "org.mpris.MediaPlayer2.mpv",
+            ],
+            true,
+        )));
+        assert_eq!(state.order, ["spotify", "firefox", "mpv"]);
+        assert_eq!(state.pinned.as_deref(), Some("spotify"));
+
+        assert!(state.update(&snapshot(
+            &["org.mpris.MediaPlayer2.mpv", "org.mpris.MediaPlayer2.vlc"],
+            false,
+        )));
+        assert_eq!(state.order, ["spotify", "firefox", "mpv", "vlc"]);
+        assert_eq!(state.pinned, None);
+        assert!(!state.update(&snapshot(&["org.mpris.MediaPlayer2.mpv"], false)));
+
+        assert_eq!(
+            state.rank("org.mpris.MediaPlayer2.firefox.instance_2_3"),
+            Some(1)
+        );
+        assert_eq!(state.rank("org.mpris.MediaPlayer2.rhythmbox"), None);
+    }
+
+    #[test]
+    fn forgets_last_players_which_left() {
+        let names = (0..MAX_SAVED_PLAYERS + 2)
+            .map(|idx| format!("org.mpris.MediaPlayer2.player{}", idx))
+            .collect::<Vec<_>>();
+        let names = names.iter().map(String::as_str).collect::<Vec<_>>();
+
+        let mut state = State::default();
+        state.update(&snapshot(&names, false));
+        assert_eq!(state.order.len(), MAX_SAVED_PLAYERS + 2);
+
+        let last = names[names.len() - 1];
+        state.update(&snapshot(&[last, "org.mpris.MediaPlayer2.vlc"], false));
+        assert_eq!(state.order.len(), MAX_SAVED_PLAYERS);
+        assert_eq!(state.order[0], "player0");
+        assert_eq!(state.rank(last), Some(MAX_SAVED_PLAYERS - 2));
+        assert_eq!(
+            state.rank("org.mpris.MediaPlayer2.vlc"),
+            Some(MAX_SAVED_PLAYERS - 1)
+        );
+        assert_eq!(state.rank(names[names.len() - 2]), None);
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let path =
+            std::env::temp_dir().join(format!("player_watcher-state-{}/state", std::process::id()));
+        assert_eq!(State::load(&path).unwrap(), State::default());
+
+        let mut state = State::default();
+        state.update(&snapshot(
+            &["org.mpris.MediaPlayer2.mpv", "org.mpris.MediaPlayer2.vlc"],
+            true,
+        ));
+        state.save(&path).unwrap();
+        assert_eq!(State::load(&path).unwrap(), state);
+        assert!(state.is_pinned("org.mpris.MediaPlayer2.mpv"));
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
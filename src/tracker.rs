@@ -0,0 +1,532 @@
+use crate::config::Config;
+use crate::players::Players;
+use crate::players_queue::PlayersQueue;
+use crate::state::State;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, PoisonError, RwLock};
+use tokio::sync::oneshot;
+
+pub type Reply<T> = oneshot::Sender<T>;
+
+/// Changes of players and requests of clients, applied one at a time in order of arrival.
+pub enum Event {
+    /// Player appeared on the bus or its name got a new owner. Its status is unknown until
+    /// reported by the player or read by the daemon.
+    Add {
+        name: Arc<str>,
+        channels: Vec<Arc<str>>,
+    },
+    Remove(Arc<str>),
+    /// All owners of a tracked player, including the queued ones.
+    SetChannels {
+        name: Arc<str>,
+        channels: Vec<Arc<str>>,
+    },
+    /// Unique connection left the bus.
+    CloseChannel(Arc<str>),
+    /// Playback status sent by a connection.
+    ChannelStatus {
+        channel: Arc<str>,
+        playing: bool,
+    },
+    /// Owners of all players were read again, after status came from an unknown `channel`.
+    LookedUp(Arc<str>),
+    /// Playback status read by the daemon, used only if the player didn't report any since
+    /// it was added.
+    PlayerStatus {
+        name: Arc<str>,
+        playing: bool,
+    },
+    /// Players present at startup were added, later ones don't take place from saved state.
+    Restored,
+    /// Config was reloaded, apply it to tracked players.
+    Reload,
+    Shift(Reply<Option<Arc<str>>>),
+    Unshift(Reply<Option<Arc<str>>>),
+    Select(String, Reply<Option<Arc<str>>>),
+    Pin(Reply<Option<Arc<str>>>),
+    Unpin(Reply<Option<Arc<str>>>),
+}
+
+/// State published to clients: players in queue order with their channels and playing state.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct Snapshot {
+    pub players: Vec<(Arc<str>, Vec<Arc<str>>, bool)>,
+    pub pinned: bool,
+}
+
+impl Snapshot {
+    pub fn active(&self) -> Option<&Arc<str>> {
+        self.players.first().map(|(name, _, _)| name)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.players.iter().any(|(n, _, _)| n.as_ref() == name)
+    }
+}
+
+/// Owner of all tracked players and their queue.
+pub struct Tracker {
+    players: Players,
+    queue: PlayersQueue,
+    config: Arc<RwLock<Config>>,
+    /// players which didn't report status since they were added
+    unknown_status: HashSet<Arc<str>>,
+    /// latest status sent by channels which are being looked up
+    pending_statuses: HashMap<Arc<str>, bool>,
+    /// saved order, players present at startup are put in it
+    saved: State,
+    /// whether added players are present at startup
+    restoring: bool,
+    /// players put in saved order, which move by it rather than as usual when their status is
+    /// first read
+    restored: HashSet<Arc<str>>,
+}
+
+impl Tracker {
+    pub fn new(config: Arc<RwLock<Config>>) -> Self {
+        Self {
+            players: Players::default(),
+            queue: PlayersQueue::default(),
+            config,
+            unknown_status: HashSet::new(),
+            pending_statuses: HashMap::new(),
+            saved: State::default(),
+            restoring: false,
+            restored: HashSet::new(),
+        }
+    }
+
+    /// Put players added until `Event::Restored` in order from `state`.
+    pub fn restore(&mut self, state: State) {
+        self.saved = state;
+        self.restoring = true;
+    }
+
+    /// Apply `event`. Return channel which isn't known, owners of players should be read again
+    /// and `Event::LookedUp` sent.
+    pub fn handle(&mut self, event: Event) -> Option<Arc<str>> {
+        match event {
+            Event::Add { name, channels } => self.add(name, channels),
+            Event::Remove(name) => {
+                self.players.remove(&name);
+                self.queue.remove_player(&name);
+                self.unknown_status.remove(&name);
+                self.restored.remove(&name);
+            }
+            Event::SetChannels { name, channels } => {
+                if self.players.contains(&name) {
+                    self.players.set_channels(name, channels);
+                }
+            }
+            Event::CloseChannel(channel) => {
+                self.players.remove_channel(&channel);
+                self.pending_statuses.remove(&channel);
+            }
+            Event::ChannelStatus { channel, playing } => {
+                return self.change_channel_status(channel, playing);
+            }
+            Event::LookedUp(channel) => {
+                if let Some(playing) = self.pending_statuses.remove(&channel) {
+                    match self.players.find_by_channel(&channel) {
+                        Some(player) => self.change_status(player, playing),
+                        None => self.players.add_unknown_channel(channel),
+                    }
+                }
+            }
+            Event::PlayerStatus { name, playing } => {
+                if !self.unknown_status.remove(&name) {
+                    return None;
+                }
+                if self.restored.remove(&name) {
+                    self.queue.set_playing(name, playing);
+                    self.promote_restored();
+                } else if self.queue.is_playing(&name) != playing {
+                    self.change_status(name, playing);
+                }
+            }
+            Event::Restored => {
+                self.restoring = false;
+                self.promote_restored();
+            }
+            Event::Reload => self.reload(),
+            Event::Shift(reply) => {
+                let _ = reply.send(self.queue.shift());
+            }
+            Event::Unshift(reply) => {
+                let _ = reply.send(self.queue.unshift());
+            }
+            Event::Select(pattern, reply) => {
+                let _ = reply.send(self.queue.select(&pattern));
+            }
+            Event::Pin(reply) => {
+                let _ = reply.send(self.queue.pin());
+            }
+            Event::Unpin(reply) => {
+                let _ = self.queue.unpin();
+                let _ = reply.send(self.queue.get_active());
+            }
+        }
+
+        None
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let players = self
+            .queue
+            .iter()
+            .map(|(name, playing)| {
+                let mut channels = self
+                    .players
+                    .get_channels(name.clone())
+                    .map(|channels| channels.iter().cloned().collect::<Vec<_>>())
+                    .unwrap_or_default();
+                channels.sort();
+                (name.clone(), channels, playing)
+            })
+            .collect();
+
+        Snapshot {
+            players,
+            pinned: self.queue.is_pinned(),
+        }
+    }
+
+    fn add(&mut self, name: Arc<str>, channels: Vec<Arc<str>>) {
+        self.unknown_status.insert(name.clone());
+
+        if !self.players.add(name.clone(), channels) {
+            return;
+        }
+        let rules = self
+            .config
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .rules_for(&name);
+
+        if !self.restoring {
+            self.queue.add_player(name, false, rules);
+            return;
+        }
+        let state = &self.saved;
+        let Some(rank) = state.rank(&name) else {
+            // players missing from saved state go last, and move as usual once their status is read
+            self.queue.insert_player(usize::MAX, name, rules);
+            return;
+        };
+
+        if state.is_pinned(&name) {
+            self.queue.insert_player(0, name.clone(), rules);
+            let _ = self.queue.pin();
+        } else {
+            // new players which started playing stay ahead, as if they were read later
+            let idx = self
+                .queue
+                .iter()
+                .position(|(n, playing)| match state.rank(n) {
+                    Some(r) => r > rank,
+                    None => !playing,
+                })
+                .unwrap_or(usize::MAX);
+            self.queue.insert_player(idx, name.clone(), rules);
+        }
+        self.restored.insert(name);
+    }
+
+    /// Put playing players ahead of the others, those in saved order as they were saved and new
+    /// ones before them.
+    fn promote_restored(&mut self) {
+        let saved = &self.saved;
+        let _ = self
+            .queue
+            .promote_playing(|name| saved.rank(name).map_or(0, |rank| rank + 1));
+    }
+
+    fn change_channel_status(&mut self, channel: Arc<str>, playing: bool) -> Option<Arc<str>> {
+        if let Some(player) = self.players.find_by_channel(&channel) {
+            self.change_status(player, playing);
+        } else if let Some(pending) = self.pending_statuses.get_mut(&channel) {
+            *pending = playing;
+        } else if !self.players.is_unknown_channel(&channel) {
+            // channel may have been opened after the player was added
+            self.pending_statuses.insert(channel.clone(), playing);
+            return Some(channel);
+        }
+
+        None
+    }
+
+    fn change_status(&mut self, player: Arc<str>, playing: bool) {
+        self.unknown_status.remove(&player);
+        self.restored.remove(&player);
+        let _ = if playing {
+            self.queue.promote(player)
+        } else {
+            self.queue.demote(player)
+        };
+    }
+
+    fn reload(&mut self) {
+        let config = self.config.read().unwrap_or_else(PoisonError::into_inner);
+
+        let names = self
+            .queue
+            .iter()
+            .map(|(n, _)| n.clone())
+            .collect::<Vec<_>>();
+        let mut rules = Vec::new();
+        for name in names {
+            let player_rules = config.rules_for(&name);
+            if player_rules.ignored {
+                self.players.remove(&name);
+                self.queue.remove_player(&name);
+                self.unknown_status.remove(&name);
+                self.restored.remove(&name);
+            } else {
+                rules.push((name, player_rules));
+            }
+        }
+
+        let _ = self.queue.set_rules(rules);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VLC: &str = "org.mpris.MediaPlayer2.vlc";
+    const MPV: &str = "org.mpris.MediaPlayer2.mpv";
+    const SPOTIFY: &str = "org.mpris.MediaPlayer2.spotify";
+    const RHYTHMBOX: &str = "org.mpris.MediaPlayer2.rhythmbox";
+
+    fn tracker() -> Tracker {
+        Tracker::new(Arc::new(RwLock::new(Config::default())))
+    }
+
+    fn add(tracker: &mut Tracker, name: &str, channel: &str) {
+        tracker.handle(Event::Add {
+            name: name.into(),
+            channels: vec![channel.into()],
+        });
+    }
+
+    fn status(tracker: &mut Tracker, channel: &str, playing: bool) -> Option<Arc<str>> {
+        tracker.handle(Event::ChannelStatus {
+            channel: channel.into(),
+            playing,
+        })
+    }
+
+    fn order(tracker: &Tracker) -> Vec<(&str, bool)> {
+        tracker
+            .queue
+            .iter()
+            .map(|(name, playing)| (name.as_ref(), playing))
+            .collect()
+    }
+
+    #[test]
+    fn applies_status_of_added_players() {
+        let mut tracker = tracker();
+        add(&mut tracker, VLC, ":1.1");
+        add(&mut tracker, MPV, ":1.2");
+        assert_eq!(order(&tracker), [(MPV, false), (VLC, false)]);
+
+        assert_eq!(status(&mut tracker, ":1.1", true), None);
+        assert_eq!(order(&tracker), [(VLC, true), (MPV, false)]);
+
+        tracker.handle(Event::Remove(VLC.into()));
+        assert_eq!(order(&tracker), [(MPV, false)]);
+    }
+
+    #[test]
+    fn prefers_reported_status_over_read_one() {
+        let mut tracker = tracker();
+        add(&mut tracker, VLC, ":1.1");
+        status(&mut tracker, ":1.1", true);
+
+        // read before the player reported it started playing
+        tracker.handle(Event::PlayerStatus {
+            name: VLC.into(),
+            playing: false,
+        });
+        assert_eq!(order(&tracker), [(VLC, true)]);
+    }
+
+    #[test]
+    fn keeps_place_on_owner_change() {
+        let mut tracker = tracker();
+        add(&mut tracker, VLC, ":1.1");
+        add(&mut tracker, MPV, ":1.2");
+        status(&mut tracker, ":1.1", true);
+
+        add(&mut tracker, VLC, ":1.3");
+        assert_eq!(order(&tracker), [(VLC, true), (MPV, false)]);
+        assert_eq!(tracker.snapshot().players[0].1, [Arc::from(":1.3")]);
+
+        tracker.handle(Event::PlayerStatus {
+            name: VLC.into(),
+            playing: false,
+        });
+        assert_eq!(order(&tracker), [(VLC, false), (MPV, false)]);
+    }
+
+    #[test]
+    fn looks_up_unknown_channels_once() {
+        let mut tracker = tracker();
+        add(&mut tracker, VLC, ":1.1");
+        add(&mut tracker, MPV, ":1.2");
+
+        assert_eq!(status(&mut tracker, ":1.3", true), Some(":1.3".into()));
+        assert_eq!(status(&mut tracker, ":1.3", false), None);
+        assert_eq!(status(&mut tracker, ":1.3", true), None);
+
+        tracker.handle(Event::SetChannels {
+            name: VLC.into(),
+            channels: vec![":1.1".into(), ":1.3".into()],
+        });
+        tracker.handle(Event::LookedUp(":1.3".into()));
+        assert_eq!(order(&tracker), [(VLC, true), (MPV, false)]);
+
+        // not owning any player
+        assert_eq!(status(&mut tracker, ":1.4", true), Some(":1.4".into()));
+        tracker.handle(Event::LookedUp(":1.4".into()));
+        assert_eq!(status(&mut tracker, ":1.4", true), None);
+        assert_eq!(order(&tracker), [(VLC, true), (MPV, false)]);
+
+        // other owner changes don't make it looked up again
+        add(&mut tracker, SPOTIFY, ":1.5");
+        assert_eq!(status(&mut tracker, ":1.4", true), None);
+
+        add(&mut tracker, RHYTHMBOX, ":1.4");
+        assert_eq!(status(&mut tracker, ":1.4", true), None);
+        assert_eq!(order(&tracker)[0], (RHYTHMBOX, true));
+    }
+
+    fn saved(names: &[&str], pinned: bool) -> State {
+        let mut state = State::default();
+        state.update(&Snapshot {
+            players: names
+                .iter()
+                .map(|&name| (Arc::from(name), Vec::new(), false))
+                .collect(),
+            pinned,
+        });
+        state
+    }
+
+    #[test]
+    fn restores_saved_order() {
+        let mut tracker = tracker();
+        tracker.restore(saved(&[MPV, SPOTIFY, VLC], true));
+        add(&mut tracker, VLC, ":1.1");
+        add(&mut tracker, RHYTHMBOX, ":1.2");
+        add(&mut tracker, MPV, ":1.3");
+        add(&mut tracker, SPOTIFY, ":1.4");
+        tracker.handle(Event::Restored);
+        assert_eq!(
+            order(&tracker),
+            [
+                (MPV, false),
+                (SPOTIFY, false),
+                (VLC, false),
+                (RHYTHMBOX, false)
+            ]
+        );
+        assert!(tracker.snapshot().pinned);
+
+        // playing players go ahead of the others, but not of the pinned one
+        tracker.handle(Event::PlayerStatus {
+            name: VLC.into(),
+            playing: true,
+        });
+        assert_eq!(order(&tracker)[0], (MPV, false));
+        assert_eq!(order(&tracker)[1], (VLC, true));
+    }
+
+    #[test]
+    fn promotes_restored_players_which_are_playing() {
+        let mut tracker = tracker();
+        tracker.restore(saved(&[MPV, SPOTIFY, VLC, RHYTHMBOX], false));
+        add(&mut tracker, MPV, ":1.1");
+        add(&mut tracker, SPOTIFY, ":1.2");
+        add(&mut tracker, VLC, ":1.3");
+        add(&mut tracker, RHYTHMBOX, ":1.4");
+        tracker.handle(Event::Restored);
+
+        let read = |tracker: &mut Tracker, name: &str, playing| {
+            tracker.handle(Event::PlayerStatus {
+                name: name.into(),
+                playing,
+            });
+        };
+        read(&mut tracker, MPV, false);
+        read(&mut tracker, RHYTHMBOX, true);
+        assert_eq!(order(&tracker)[0], (RHYTHMBOX, true));
+
+        // playing ones keep saved order among them, regardless of when their status is read
+        read(&mut tracker, VLC, true);
+        read(&mut tracker, SPOTIFY, false);
+        assert_eq!(
+            order(&tracker),
+            [
+                (VLC, true),
+                (RHYTHMBOX, true),
+                (MPV, false),
+                (SPOTIFY, false)
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_new_playing_players_ahead() {
+        let mut tracker = tracker();
+        tracker.restore(saved(&[MPV, VLC], false));
+        add(&mut tracker, RHYTHMBOX, ":1.1");
+        tracker.handle(Event::PlayerStatus {
+            name: RHYTHMBOX.into(),
+            playing: true,
+        });
+        add(&mut tracker, VLC, ":1.2");
+        add(&mut tracker, MPV, ":1.3");
+        assert_eq!(
+            order(&tracker),
+            [(RHYTHMBOX, true), (MPV, false), (VLC, false)]
+        );
+    }
+
+    #[test]
+    fn adds_players_as_usual_after_restoring() {
+        let mut tracker = tracker();
+        tracker.restore(saved(&[MPV], false));
+        add(&mut tracker, VLC, ":1.1");
+        status(&mut tracker, ":1.1", true);
+        tracker.handle(Event::Restored);
+
+        add(&mut tracker, MPV, ":1.2");
+        assert_eq!(order(&tracker), [(VLC, true), (MPV, false)]);
+    }
+
+    #[test]
+    fn replies_to_requests() {
+        let mut tracker = tracker();
+        add(&mut tracker, VLC, ":1.1");
+        add(&mut tracker, MPV, ":1.2");
+
+        let (reply, mut receiver) = oneshot::channel();
+        tracker.handle(Event::Shift(reply));
+        assert_eq!(receiver.try_recv().unwrap(), Some(VLC.into()));
+
+        let (reply, mut receiver) = oneshot::channel();
+        tracker.handle(Event::Pin(reply));
+        assert_eq!(receiver.try_recv().unwrap(), Some(VLC.into()));
+        status(&mut tracker, ":1.2", true);
+        assert_eq!(tracker.snapshot().active(), Some(&VLC.into()));
+
+        let (reply, mut receiver) = oneshot::channel();
+        tracker.handle(Event::Unpin(reply));
+        assert_eq!(receiver.try_recv().unwrap(), Some(MPV.into()));
+        assert!(!tracker.snapshot().pinned);
+    }
+}